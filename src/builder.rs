@@ -0,0 +1,935 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Output};
+
+use users::User;
+
+use crate::env::CaptureOptions;
+use crate::locale::system_locale;
+use crate::output::stream_lines;
+#[cfg(target_os = "linux")]
+use crate::pre_exec::set_no_new_privs;
+use crate::pre_exec::{detach, set_nice, set_parent_death_signal, set_umask, PrivilegeDrop};
+#[cfg(feature = "pam")]
+use crate::PamEnvPrecedence;
+use crate::{
+    home_dir, user_command_with_drop, CaptureBudget, CmdError, EnvBackend, EnvPolicy, EnvSanitizer,
+    GroupPolicy, NologinPolicy, PassthroughVars, Resource, Rlimits, StreamKind,
+};
+
+/// Configures a command to run as a user, one concern at a time.
+///
+/// The `cmd_as_user_with_*` functions each add one setting to `cmd_as_user`;
+/// this combines any of them. Everything not set here behaves as in
+/// `cmd_as_user`: the user's captured environment cleaned by the default
+/// `EnvSanitizer`, the user's home directory as working directory, their
+/// supplementary groups, the caller's umask and session.
+///
+/// The child's environment is put together in this order, each step
+/// overriding the ones before it:
+///
+/// 1. the user's captured environment, cleaned by the sanitizer; how it is
+///    captured is set with `backend`, `fallback_shell`, `nologin_policy`
+///    and `capture_budget`;
+/// 2. combined with the caller's environment according to `env_policy`;
+/// 3. with the `pam` feature, the PAM session's variables given to
+///    `pam_env`, over or under those as its `PamEnvPrecedence` says;
+/// 4. variables named in `preserve_env`, only where still unset;
+/// 5. with `system_locale`, the locale from the system's locale file, only
+///    where still unset;
+/// 6. variables named in `passthrough`;
+/// 7. variables set with `env`.
+///
+/// So a variable set with `env` is always passed on, even one the sanitizer
+/// denies.
+///
+/// The settings that take effect between fork and exec are applied in a
+/// fixed order, whatever order they were set in: `nice`, then the switch to
+/// the user (after `drop_capabilities` where asked for) and into the working
+/// directory, then `umask`, the
+/// `limit`s, the new session or process group, `parent_death_signal` and
+/// finally `no_new_privs`. The first one that fails makes `spawn` fail with
+/// its error.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::CommandBuilder;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let mut cmd = CommandBuilder::new()
+///     .program("Rscript")
+///     .user(user)
+///     .preserve_env(&["TERM", "LC_*"])
+///     .umask(0o077)
+///     .working_dir("/srv/projects/example")
+///     .supplementary_groups(false)
+///     .detached(true)
+///     .build()
+///     .unwrap();
+/// let child = cmd.arg("job.R").spawn().unwrap();
+/// ```
+///
+/// Explicit values win over everything else:
+///
+/// ```
+/// use polyjuice::{CommandBuilder, EnvPolicy};
+///
+/// std::env::set_var("OMP_NUM_THREADS", "16");
+/// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+/// let cmd = CommandBuilder::new()
+///     .program("Rscript")
+///     .args(["-e", "1 + 1"])
+///     .user(user)
+///     .env_policy(EnvPolicy::InheritCaller)
+///     .env("OMP_NUM_THREADS", "4")
+///     .env("LD_LIBRARY_PATH", "/opt/R/lib")
+///     .build()
+///     .unwrap();
+/// let envs: Vec<_> = cmd.get_envs().collect();
+/// assert!(envs.contains(&("OMP_NUM_THREADS".as_ref(), Some("4".as_ref()))));
+/// assert!(envs.contains(&("LD_LIBRARY_PATH".as_ref(), Some("/opt/R/lib".as_ref()))));
+/// assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["-e", "1 + 1"]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CommandBuilder {
+    program: Option<OsString>,
+    args: Vec<OsString>,
+    user: Option<User>,
+    capture: CaptureOptions,
+    sanitizer: EnvSanitizer,
+    policy: EnvPolicy,
+    preserve: Vec<String>,
+    system_locale: bool,
+    #[cfg(feature = "pam")]
+    pam_env: Option<(HashMap<String, String>, PamEnvPrecedence)>,
+    passthrough: Vec<String>,
+    overrides: Vec<(OsString, OsString)>,
+    umask: Option<u32>,
+    rlimits: Rlimits,
+    nice: Option<i32>,
+    working_dir: Option<PathBuf>,
+    keep_current_dir: bool,
+    groups: GroupPolicy,
+    detached: bool,
+    own_process_group: bool,
+    parent_death_signal: Option<i32>,
+    #[cfg(target_os = "linux")]
+    no_new_privs: bool,
+    #[cfg(target_os = "linux")]
+    drop_capabilities: bool,
+    #[cfg(target_os = "linux")]
+    keep_inherited_capabilities: bool,
+}
+
+impl CommandBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The program to run. Required.
+    pub fn program(&mut self, program: impl AsRef<OsStr>) -> &mut Self {
+        self.program = Some(program.as_ref().to_os_string());
+        self
+    }
+
+    /// Adds an argument to pass to the program.
+    pub fn arg(&mut self, arg: impl AsRef<OsStr>) -> &mut Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Adds arguments to pass to the program.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_os_string()));
+        self
+    }
+
+    /// The user to run it as. Required.
+    pub fn user(&mut self, user: User) -> &mut Self {
+        self.user = Some(user);
+        self
+    }
+
+    /// Bound environment capture by `budget`, as
+    /// `cmd_as_user_with_capture_budget` does. Defaults to
+    /// `DEFAULT_CAPTURE_TIMEOUT` with no limit on output.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    /// use polyjuice::{BudgetPolicy, CaptureBudget, CommandBuilder};
+    ///
+    /// let user = users::get_user_by_name("example_user").unwrap();
+    /// let cmd = CommandBuilder::new()
+    ///     .program("R")
+    ///     .user(user)
+    ///     .capture_budget(CaptureBudget {
+    ///         max_duration: Duration::from_secs(5),
+    ///         max_bytes: 256 * 1024,
+    ///         on_exceeded: BudgetPolicy::Partial,
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn capture_budget(&mut self, budget: CaptureBudget) -> &mut Self {
+        self.capture.budget = budget;
+        self
+    }
+
+    /// Capture the environment through `backend`, as
+    /// `cmd_as_user_with_backend` does.
+    ///
+    /// By default `su` is used, and `runuser` where `su` is missing or asks
+    /// for a password. A backend set here is used on its own.
+    pub fn backend(&mut self, backend: EnvBackend) -> &mut Self {
+        self.capture.backend = Some(backend);
+        self
+    }
+
+    /// Capture with `shell` if the user's login shell is missing or not
+    /// executable, as `cmd_as_user_with_fallback_shell` does. Defaults to
+    /// `DEFAULT_FALLBACK_SHELL`.
+    pub fn fallback_shell(&mut self, shell: impl Into<PathBuf>) -> &mut Self {
+        self.capture.fallback_shell = shell.into();
+        self
+    }
+
+    /// Handle a login shell that refuses logins according to `policy`; see
+    /// `NologinPolicy`. Defaults to `NologinPolicy::Reject`.
+    pub fn nologin_policy(&mut self, policy: NologinPolicy) -> &mut Self {
+        self.capture.nologin = policy;
+        self
+    }
+
+    /// Clean the captured environment with `sanitizer` instead of the
+    /// default one.
+    pub fn sanitizer(&mut self, sanitizer: EnvSanitizer) -> &mut Self {
+        self.sanitizer = sanitizer;
+        self
+    }
+
+    /// Combine the user's environment with the caller's according to
+    /// `policy`, as `cmd_as_user_with_policy` does. Defaults to
+    /// `EnvPolicy::Replace`.
+    pub fn env_policy(&mut self, policy: EnvPolicy) -> &mut Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Keep the caller's variables named in `vars` unless the user's
+    /// environment sets them too, as `cmd_as_user_preserving` does.
+    pub fn preserve_env(&mut self, vars: &[&str]) -> &mut Self {
+        self.preserve.extend(vars.iter().map(|v| v.to_string()));
+        self
+    }
+
+    /// Fill in locale variables (`LANG`, `LANGUAGE`, `LC_*`) the environment
+    /// lacks from the system-wide locale, as a login through `pam_env` would.
+    /// Off by default.
+    ///
+    /// Login shells often leave the locale to PAM, so a captured environment
+    /// can come without `LANG`, and programs then fall back to the `C`
+    /// locale and mangle UTF-8. The values come from the first of
+    /// `SYSTEM_LOCALE_FILES` that exists. A final `LANG` therefore comes
+    /// from, in order: `env`, `passthrough`, the user's environment (or the
+    /// caller's, as `env_policy` decides), `preserve_env`, and only then the
+    /// locale file. To take the caller's locale instead, preserve it with
+    /// `preserve_env(&["LANG", "LANGUAGE", "LC_*"])`; with both, the caller's
+    /// wins.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use polyjuice::CommandBuilder;
+    ///
+    /// let user = users::get_user_by_name("example_user").unwrap();
+    /// let cmd = CommandBuilder::new()
+    ///     .program("R")
+    ///     .user(user)
+    ///     .system_locale(true)
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn system_locale(&mut self, fill: bool) -> &mut Self {
+        self.system_locale = fill;
+        self
+    }
+
+    /// Merge `pam_env`, the environment of a PAM session, into the user's.
+    ///
+    /// Modules such as `pam_env` and `pam_systemd` set variables a login
+    /// would pass on (`XDG_RUNTIME_DIR`, `KRB5CCNAME`), which the captured
+    /// environment does not have; get them from `PamSessionGuard::env` or
+    /// `try_pam_session_env`. With `PamEnvPrecedence::Pam`, as during a real
+    /// login, the session's value wins where both set a variable; with
+    /// `PamEnvPrecedence::User`, the session only fills in what the user's
+    /// environment lacks. A later call replaces an earlier one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use polyjuice::{CommandBuilder, EnvPolicy, PamEnvPrecedence};
+    ///
+    /// std::env::set_var("TMPDIR", "/tmp");
+    /// let pam_env = HashMap::from([
+    ///     ("TMPDIR".to_string(), "/run/user/1000/tmp".to_string()),
+    ///     ("XDG_RUNTIME_DIR".to_string(), "/run/user/1000".to_string()),
+    /// ]);
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let mut builder = CommandBuilder::new();
+    /// builder
+    ///     .program("printenv")
+    ///     .args(["TMPDIR", "XDG_RUNTIME_DIR"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller);
+    ///
+    /// let output = builder.pam_env(pam_env.clone(), PamEnvPrecedence::Pam).output().unwrap();
+    /// let stdout = String::from_utf8(output.stdout).unwrap();
+    /// assert_eq!(stdout, "/run/user/1000/tmp\n/run/user/1000\n");
+    ///
+    /// let output = builder.pam_env(pam_env, PamEnvPrecedence::User).output().unwrap();
+    /// let stdout = String::from_utf8(output.stdout).unwrap();
+    /// assert_eq!(stdout, "/tmp\n/run/user/1000\n");
+    /// ```
+    #[cfg(feature = "pam")]
+    pub fn pam_env(
+        &mut self,
+        pam_env: HashMap<String, String>,
+        precedence: PamEnvPrecedence,
+    ) -> &mut Self {
+        self.pam_env = Some((pam_env, precedence));
+        self
+    }
+
+    /// Pass the caller's variables named in `vars` through, over the user's
+    /// value, as `cmd_as_user_with_passthrough` does.
+    pub fn passthrough(&mut self, vars: &[&str]) -> &mut Self {
+        self.passthrough.extend(vars.iter().map(|v| v.to_string()));
+        self
+    }
+
+    /// Set `key` to `value` in the child, over anything the user's or the
+    /// caller's environment has for it.
+    pub fn env(&mut self, key: impl AsRef<OsStr>, value: impl AsRef<OsStr>) -> &mut Self {
+        self.overrides
+            .push((key.as_ref().to_os_string(), value.as_ref().to_os_string()));
+        self
+    }
+
+    /// Run with the umask `mode`, as `cmd_as_user_with_umask` does.
+    ///
+    /// The mask is set after the switch to the user, just before exec, and
+    /// only in the child. `build` fails with `CmdError::InvalidUmask` if
+    /// `mode` is larger than `0o777`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{CommandBuilder, EnvPolicy};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let output = CommandBuilder::new()
+    ///     .program("sh")
+    ///     .args(["-c", "umask"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller)
+    ///     .umask(0o027)
+    ///     .output()
+    ///     .unwrap();
+    /// assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "0027");
+    /// ```
+    pub fn umask(&mut self, mode: u32) -> &mut Self {
+        self.umask = Some(mode);
+        self
+    }
+
+    /// Limit `resource` to `soft`, with `hard` as the ceiling; see `Rlimits`.
+    ///
+    /// The limits are set after the switch to the user, so unless the user is
+    /// root, a lowered hard limit cannot be raised again by the child or
+    /// anything it starts.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{CommandBuilder, EnvPolicy, Resource};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let output = CommandBuilder::new()
+    ///     .program("sh")
+    ///     .args(["-c", "ulimit -Sn; ulimit -Hn"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller)
+    ///     .limit(Resource::Nofile, 32, 128)
+    ///     .output()
+    ///     .unwrap();
+    /// let stdout = String::from_utf8(output.stdout).unwrap();
+    /// assert_eq!(stdout.lines().collect::<Vec<_>>(), ["32", "128"]);
+    /// ```
+    pub fn limit(&mut self, resource: Resource, soft: u64, hard: u64) -> &mut Self {
+        self.rlimits.limit(resource, soft, hard);
+        self
+    }
+
+    /// Run with the nice value `nice`, from -20 (highest priority) to 19
+    /// (lowest).
+    ///
+    /// The value is set before the switch to the user, so a caller running
+    /// as root may also raise the priority. Without root or `CAP_SYS_NICE`
+    /// a negative value makes spawning fail with `EACCES` rather than
+    /// running the command at the wrong priority. `build` fails with
+    /// `CmdError::InvalidNice` for a value outside -20..=19.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use polyjuice::CommandBuilder;
+    ///
+    /// let user = users::get_user_by_name("example_user").unwrap();
+    /// let child = CommandBuilder::new()
+    ///     .program("Rscript")
+    ///     .arg("batch.R")
+    ///     .user(user)
+    ///     .nice(10)
+    ///     .spawn()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// The child sees the value, here in field 19 of `/proc/self/stat`:
+    ///
+    /// ```
+    /// use polyjuice::{CommandBuilder, EnvPolicy};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let output = CommandBuilder::new()
+    ///     .program("cut")
+    ///     .args(["-d", " ", "-f", "19", "/proc/self/stat"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller)
+    ///     .nice(19)
+    ///     .output()
+    ///     .unwrap();
+    /// assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "19");
+    /// ```
+    pub fn nice(&mut self, nice: i32) -> &mut Self {
+        self.nice = Some(nice);
+        self
+    }
+
+    /// Run in `dir` instead of the user's home directory.
+    ///
+    /// The directory is entered after the switch to the user, so spawning
+    /// fails if the user may not enter it, even when the caller could.
+    pub fn working_dir(&mut self, dir: impl Into<PathBuf>) -> &mut Self {
+        self.working_dir = Some(dir.into());
+        self
+    }
+
+    /// Whether to run in the caller's working directory instead of the
+    /// user's home directory. Ignored if `working_dir` is set.
+    ///
+    /// By default the command starts in the home directory, as a login
+    /// would, since the caller's directory is often one the user cannot
+    /// access. If the home directory does not exist the working directory
+    /// is left as it is and a warning is logged.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use users::os::unix::UserExt;
+    /// use polyjuice::{CommandBuilder, EnvPolicy};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let home = user.home_dir().canonicalize().unwrap();
+    /// let pwd = |keep| {
+    ///     let output = CommandBuilder::new()
+    ///         .program("pwd")
+    ///         .arg("-P")
+    ///         .user(user.clone())
+    ///         .env_policy(EnvPolicy::InheritCaller)
+    ///         .keep_current_dir(keep)
+    ///         .output()
+    ///         .unwrap();
+    ///     String::from_utf8(output.stdout).unwrap()
+    /// };
+    /// assert_eq!(pwd(false).trim_end(), home.to_str().unwrap());
+    /// assert_eq!(
+    ///     pwd(true).trim_end(),
+    ///     std::env::current_dir().unwrap().canonicalize().unwrap().to_str().unwrap()
+    /// );
+    /// ```
+    pub fn keep_current_dir(&mut self, keep: bool) -> &mut Self {
+        self.keep_current_dir = keep;
+        self
+    }
+
+    /// Whether to give the command the user's supplementary groups
+    /// (`GroupPolicy::Supplementary`, the default) or only their primary
+    /// group (`GroupPolicy::PrimaryOnly`).
+    pub fn supplementary_groups(&mut self, supplementary: bool) -> &mut Self {
+        self.groups = match supplementary {
+            true => GroupPolicy::Supplementary,
+            false => GroupPolicy::PrimaryOnly,
+        };
+        self
+    }
+
+    /// Whether to run the command in a session of its own; see `detach`.
+    ///
+    /// # Examples
+    ///
+    /// The child leads its own session, here read from fields 1 (the pid)
+    /// and 6 (the session) of `/proc/self/stat`:
+    ///
+    /// ```
+    /// use polyjuice::{CommandBuilder, EnvPolicy};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let output = CommandBuilder::new()
+    ///     .program("cut")
+    ///     .args(["-d", " ", "-f", "1,6", "/proc/self/stat"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller)
+    ///     .detached(true)
+    ///     .output()
+    ///     .unwrap();
+    /// let stdout = String::from_utf8(output.stdout).unwrap();
+    /// let (pid, sid) = stdout.trim().split_once(' ').unwrap();
+    /// assert_eq!(sid, pid);
+    /// assert_ne!(sid.parse::<i32>().unwrap(), unsafe { libc::getsid(0) });
+    /// ```
+    pub fn detached(&mut self, detached: bool) -> &mut Self {
+        self.detached = detached;
+        self
+    }
+
+    /// Whether to run the command in a process group of its own, within the
+    /// caller's session.
+    ///
+    /// This is the lighter form of `detached`: signals sent to the caller's
+    /// process group, such as the `SIGINT` from a Ctrl-C in the caller's
+    /// terminal, no longer reach the command, but it keeps the controlling
+    /// terminal and may still get a `SIGHUP` when the session ends. Ignored
+    /// if `detached` is set, which makes the command a process group leader
+    /// anyway.
+    pub fn own_process_group(&mut self, own: bool) -> &mut Self {
+        self.own_process_group = own;
+        self
+    }
+
+    /// Have the kernel send `signal` (e.g. `libc::SIGTERM` or `libc::SIGKILL`)
+    /// to the command when its parent goes away, so that jobs do not outlive
+    /// a supervisor that crashed.
+    ///
+    /// This uses `PR_SET_PDEATHSIG`, which is Linux only; elsewhere spawning
+    /// fails with `io::ErrorKind::Unsupported`. It is set after the switch to
+    /// the user, since changing credentials clears it.
+    ///
+    /// The "parent" is the thread that spawned the command, not the whole
+    /// process: spawning from a short-lived thread (including a
+    /// `spawn_blocking` task in an async runtime) signals the command as soon
+    /// as that thread exits. Spawn from a thread that lives as long as the
+    /// supervisor. The setting is also cleared in the command if it executes
+    /// a setuid or setgid binary.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use polyjuice::CommandBuilder;
+    ///
+    /// let user = users::get_user_by_name("example_user").unwrap();
+    /// let child = CommandBuilder::new()
+    ///     .program("Rscript")
+    ///     .arg("worker.R")
+    ///     .user(user)
+    ///     .parent_death_signal(libc::SIGTERM)
+    ///     .spawn()
+    ///     .unwrap();
+    /// ```
+    pub fn parent_death_signal(&mut self, signal: i32) -> &mut Self {
+        self.parent_death_signal = Some(signal);
+        self
+    }
+
+    /// Whether to set `PR_SET_NO_NEW_PRIVS` in the child, so that it cannot
+    /// gain privileges by running setuid or setgid binaries (`sudo`, `su`,
+    /// `passwd`) or binaries with file capabilities.
+    ///
+    /// The flag is inherited by everything the command starts and cannot be
+    /// unset. It needs Linux 3.5 or later; on older kernels spawning fails
+    /// with `EINVAL`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{CommandBuilder, EnvPolicy};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let output = CommandBuilder::new()
+    ///     .program("sh")
+    ///     .args(["-c", "grep NoNewPrivs /proc/self/status"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller)
+    ///     .no_new_privs(true)
+    ///     .output()
+    ///     .unwrap();
+    /// let status = String::from_utf8(output.stdout).unwrap();
+    /// assert_eq!(status.split_whitespace().collect::<Vec<_>>(), ["NoNewPrivs:", "1"]);
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn no_new_privs(&mut self, no_new_privs: bool) -> &mut Self {
+        self.no_new_privs = no_new_privs;
+        self
+    }
+
+    /// Whether to also empty the child's bounding capability set before it
+    /// switches to the user, so it runs with exactly the user's
+    /// privileges even if it executes a setuid-root binary.
+    ///
+    /// The bounding set has existed since Linux 2.6.25. Dropping from it
+    /// needs `CAP_SETPCAP`, so when not running as root spawning fails with
+    /// `EPERM`. The ambient and inheritable sets are emptied anyway, see
+    /// `keep_inherited_capabilities`.
+    #[cfg(target_os = "linux")]
+    pub fn drop_capabilities(&mut self, drop_capabilities: bool) -> &mut Self {
+        self.drop_capabilities = drop_capabilities;
+        self
+    }
+
+    /// Whether to leave the child's ambient and inheritable capability sets
+    /// as the caller's are.
+    ///
+    /// Changing uid does not empty them for a caller that is not root but
+    /// holds capabilities (e.g. `CAP_SETUID` and `CAP_DAC_OVERRIDE` from a
+    /// systemd unit), so ambient capabilities would survive into the
+    /// command. By default both sets are therefore emptied, which needs no
+    /// privileges; a failure makes spawning fail.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{CommandBuilder, EnvPolicy};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let output = CommandBuilder::new()
+    ///     .program("grep")
+    ///     .args(["-E", "^Cap(Amb|Inh)", "/proc/self/status"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller)
+    ///     .output()
+    ///     .unwrap();
+    /// let status = String::from_utf8(output.stdout).unwrap();
+    /// for line in status.lines() {
+    ///     assert!(line.ends_with("0000000000000000"), "{}", line);
+    /// }
+    /// ```
+    #[cfg(target_os = "linux")]
+    pub fn keep_inherited_capabilities(&mut self, keep: bool) -> &mut Self {
+        self.keep_inherited_capabilities = keep;
+        self
+    }
+
+    /// Captures the user's environment and creates the command.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CmdError::MissingBuilderField` naming the `user` or
+    /// `program` if either was not set, `CmdError::InvalidUmask` for a umask
+    /// larger than `0o777`, `CmdError::InvalidNice` for a nice value outside
+    /// -20..=19, `CmdError::FailedGettingEnv` if the environment could not
+    /// be captured, and `CmdError::GroupsNotSettable` if the caller is not
+    /// root and does not already have the groups the command needs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{CmdError, CommandBuilder};
+    ///
+    /// let err = CommandBuilder::new().program("R").build().unwrap_err();
+    /// assert!(matches!(err, CmdError::MissingBuilderField("user")));
+    /// ```
+    pub fn build(&self) -> Result<Command, CmdError> {
+        let user = self
+            .user
+            .as_ref()
+            .ok_or(CmdError::MissingBuilderField("user"))?;
+        let program = self
+            .program
+            .as_ref()
+            .ok_or(CmdError::MissingBuilderField("program"))?;
+        self.command(program, user)
+    }
+
+    /// Builds the command and spawns it.
+    ///
+    /// # Errors
+    ///
+    /// As for `build`, and `CmdError::SpawnFailed` if the command could not
+    /// be started.
+    pub fn spawn(&self) -> Result<Child, CmdError> {
+        self.build()?.spawn().map_err(CmdError::SpawnFailed)
+    }
+
+    /// Like `build`, but without blocking the async runtime, for a
+    /// `tokio::process::Command`.
+    ///
+    /// Building captures the environment and looks up the user's groups,
+    /// which can take as long as the login profile or NSS do; both run on
+    /// tokio's blocking pool. Must be called within a tokio runtime.
+    ///
+    /// # Errors
+    ///
+    /// As for `build`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async fn example() {
+    /// use polyjuice::CommandBuilder;
+    ///
+    /// let user = users::get_user_by_name("example_user").unwrap();
+    /// let status = CommandBuilder::new()
+    ///     .program("R")
+    ///     .user(user)
+    ///     .build_async()
+    ///     .await
+    ///     .unwrap()
+    ///     .status()
+    ///     .await;
+    /// # }
+    /// ```
+    #[cfg(feature = "tokio")]
+    pub async fn build_async(&self) -> Result<tokio::process::Command, CmdError> {
+        let builder = self.clone();
+        match tokio::task::spawn_blocking(move || builder.build()).await {
+            Ok(cmd) => cmd.map(Into::into),
+            Err(e) => std::panic::resume_unwind(e.into_panic()),
+        }
+    }
+
+    /// Builds the command, runs it to completion and collects its output.
+    ///
+    /// # Errors
+    ///
+    /// As for `spawn`.
+    pub fn output(&self) -> Result<Output, CmdError> {
+        self.build()?.output().map_err(CmdError::SpawnFailed)
+    }
+
+    /// Builds the command, runs it to completion and calls `on_stdout` or
+    /// `on_stderr` for every line it writes.
+    ///
+    /// Lines are delivered as for `run_as_user_streaming`: on the calling
+    /// thread, in the order they were read, without the trailing newline and
+    /// converted lossily if they are not valid UTF-8. Both streams are read
+    /// concurrently, so a child filling one pipe while this waits on the
+    /// other cannot deadlock. Returns once the child has exited and both
+    /// streams have been drained.
+    ///
+    /// # Errors
+    ///
+    /// As for `spawn`, and `CmdError::SpawnFailed` if the output could not
+    /// be read or the child waited on.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{CommandBuilder, EnvPolicy};
+    ///
+    /// let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+    /// let (mut out, mut err) = (Vec::new(), Vec::new());
+    /// let status = CommandBuilder::new()
+    ///     .program("sh")
+    ///     .args(["-c", "echo one; echo two >&2; printf 'th\\377ree'"])
+    ///     .user(user)
+    ///     .env_policy(EnvPolicy::InheritCaller)
+    ///     .spawn_streaming(
+    ///         |line| out.push(line.to_string()),
+    ///         |line| err.push(line.to_string()),
+    ///     )
+    ///     .unwrap();
+    /// assert!(status.success());
+    /// assert_eq!(out, ["one", "th\u{FFFD}ree"]);
+    /// assert_eq!(err, ["two"]);
+    /// ```
+    pub fn spawn_streaming(
+        &self,
+        mut on_stdout: impl FnMut(&str),
+        mut on_stderr: impl FnMut(&str),
+    ) -> Result<ExitStatus, CmdError> {
+        stream_lines(&mut self.build()?, |kind, line| match kind {
+            StreamKind::Stdout => on_stdout(line),
+            StreamKind::Stderr => on_stderr(line),
+        })
+    }
+
+    /// Creates the command for `program` and `user`, ignoring the ones set
+    /// on the builder. This is what the `cmd_as_user` family delegates to.
+    pub(crate) fn command(
+        &self,
+        program: impl AsRef<OsStr>,
+        user: &User,
+    ) -> Result<Command, CmdError> {
+        let env = self.environment(user)?;
+        self.command_with_env(program, user, &env)
+    }
+
+    /// The command `build` would create, with an empty environment instead
+    /// of a captured one, for `verify_launch_plan`. The program does not
+    /// have to be set, as the command is never executed.
+    pub(crate) fn build_without_env(&self) -> Result<Command, CmdError> {
+        let user = self
+            .user
+            .as_ref()
+            .ok_or(CmdError::MissingBuilderField("user"))?;
+        let program = self.program.as_deref().unwrap_or(OsStr::new("/bin/true"));
+        self.command_with_env(program, user, &HashMap::new())
+    }
+
+    /// Rejects settings no command can be built with. Checked before
+    /// anything is captured.
+    fn validate(&self) -> Result<(), CmdError> {
+        if let Some(mode) = self.umask.filter(|&mode| mode > 0o777) {
+            return Err(CmdError::InvalidUmask(mode));
+        }
+        if let Some(nice) = self.nice.filter(|nice| !(-20..=19).contains(nice)) {
+            return Err(CmdError::InvalidNice(nice));
+        }
+        Ok(())
+    }
+
+    /// The environment the command for `user` gets before `passthrough`
+    /// and `env` are applied: steps 1 to 5 of the order described on the
+    /// type.
+    pub(crate) fn environment(&self, user: &User) -> Result<HashMap<OsString, OsString>, CmdError> {
+        self.validate()?;
+        let mut captured = match self.policy.needs_capture() {
+            true => self
+                .capture
+                .capture(user.name())
+                .map_err(CmdError::FailedGettingEnv)?,
+            false => HashMap::new(),
+        };
+        self.sanitizer.sanitize(&mut captured);
+        let mut env = self.policy.apply(captured);
+        #[cfg(feature = "pam")]
+        if let Some((pam_env, precedence)) = &self.pam_env {
+            precedence.merge(&mut env, pam_env);
+        }
+        if !self.preserve.is_empty() {
+            for (key, value) in passthrough_vars(&self.preserve).caller_vars() {
+                env.entry(key).or_insert(value);
+            }
+        }
+        if self.system_locale {
+            for (key, value) in system_locale() {
+                env.entry(key.into()).or_insert(value.into());
+            }
+        }
+        Ok(env)
+    }
+
+    /// Creates the command for `program` and `user` with `env`, as returned
+    /// by `environment`.
+    pub(crate) fn command_with_env(
+        &self,
+        program: impl AsRef<OsStr>,
+        user: &User,
+        env: &HashMap<OsString, OsString>,
+    ) -> Result<Command, CmdError> {
+        self.validate()?;
+        let drop = PrivilegeDrop::to_user(user, self.groups);
+        #[cfg(target_os = "linux")]
+        let drop = match self.drop_capabilities {
+            true => drop.dropping_capabilities(),
+            false => drop,
+        };
+        #[cfg(target_os = "linux")]
+        let drop = match self.keep_inherited_capabilities {
+            true => drop.keeping_inherited_capabilities(),
+            false => drop,
+        };
+        drop.check()?;
+        let drop = match &self.working_dir {
+            Some(dir) => drop.in_dir(dir),
+            None if self.keep_current_dir => drop,
+            None => match home_dir(user) {
+                Some(home) => drop.in_dir(home),
+                None => drop,
+            },
+        };
+        let mut new_cmd = Command::new(program);
+        if let Some(nice) = self.nice {
+            // before the drop, while raising the priority is still allowed
+            set_nice(&mut new_cmd, nice);
+        }
+        let mut new_cmd = user_command_with_drop(new_cmd, env, drop);
+        passthrough_vars(&self.passthrough).apply(&mut new_cmd);
+        new_cmd.envs(self.overrides.iter().map(|(k, v)| (k, v)));
+        new_cmd.args(&self.args);
+        if let Some(mode) = self.umask {
+            set_umask(&mut new_cmd, mode);
+        }
+        self.rlimits.apply(&mut new_cmd);
+        if self.detached {
+            detach(&mut new_cmd);
+        } else if self.own_process_group {
+            new_cmd.process_group(0);
+        }
+        if let Some(signal) = self.parent_death_signal {
+            set_parent_death_signal(&mut new_cmd, signal);
+        }
+        #[cfg(target_os = "linux")]
+        if self.no_new_privs {
+            set_no_new_privs(&mut new_cmd);
+        }
+        Ok(new_cmd)
+    }
+}
+
+fn passthrough_vars(patterns: &[String]) -> PassthroughVars {
+    let patterns: Vec<&str> = patterns.iter().map(String::as_str).collect();
+    PassthroughVars::new(&patterns)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn working_dir_is_entered_as_the_user() {
+        if users::get_effective_uid() != 0 {
+            // only root can run a command as another user
+            return;
+        }
+        let nobody = users::get_user_by_name("nobody").unwrap();
+        let dir = std::env::temp_dir().join(format!("polyjuice-root-only-{}", std::process::id()));
+        fs::create_dir(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        let result = CommandBuilder::new()
+            .program("true")
+            .user(nobody)
+            .env_policy(EnvPolicy::InheritCaller)
+            .working_dir(&dir)
+            .spawn();
+        fs::remove_dir(&dir).unwrap();
+        match result {
+            Err(CmdError::SpawnFailed(e)) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            other => panic!("expected a failed spawn, got {:?}", other.map(|_| ())),
+        }
+    }
+}
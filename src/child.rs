@@ -0,0 +1,136 @@
+use std::ffi::OsStr;
+use std::ops::{Deref, DerefMut};
+use std::process::Child;
+
+use users::User;
+
+use crate::{cmd_as_user, CmdError};
+
+/// A child process that is killed when its handle is dropped.
+///
+/// An error path that returns early would otherwise leave the child
+/// running, owned by the target user with nobody waiting for it. When a
+/// `UserChild` is dropped before the child has been waited on, the child is
+/// sent `SIGKILL` and then reaped, so it leaves no zombie either. This is
+/// what tokio's `Command::kill_on_drop` does for async children.
+///
+/// Only the child itself is killed, not processes it started. A child that
+/// is meant to outlive the handle, such as a detached daemon, should turn
+/// this off with `kill_on_drop(false)`, or be taken out with `into_inner`.
+///
+/// The handle dereferences to the `std::process::Child`, so `id`, `stdin`,
+/// `wait` and the rest work as usual. A child that has been waited on (by
+/// `wait` or a `try_wait` that returned a status) is left alone on drop.
+///
+/// # Examples
+///
+/// ```
+/// use std::path::Path;
+/// use std::process::Command;
+/// use polyjuice::UserChild;
+///
+/// let child = UserChild::from(Command::new("sleep").arg("30").spawn().unwrap());
+/// let proc_dir = format!("/proc/{}", child.id());
+/// assert!(Path::new(&proc_dir).exists());
+///
+/// drop(child);
+/// assert!(!Path::new(&proc_dir).exists());
+/// ```
+#[derive(Debug)]
+pub struct UserChild {
+    // only `None` once `into_inner` has taken the child
+    child: Option<Child>,
+    kill_on_drop: bool,
+}
+
+impl UserChild {
+    /// Whether to kill the child on drop. On by default.
+    pub fn kill_on_drop(&mut self, kill: bool) -> &mut Self {
+        self.kill_on_drop = kill;
+        self
+    }
+
+    /// Returns the `std::process::Child`, which is no longer killed on drop.
+    ///
+    /// Use this for the `Child` methods that take it by value, such as
+    /// `wait_with_output`.
+    pub fn into_inner(mut self) -> Child {
+        self.child.take().unwrap()
+    }
+}
+
+impl From<Child> for UserChild {
+    fn from(child: Child) -> Self {
+        UserChild {
+            child: Some(child),
+            kill_on_drop: true,
+        }
+    }
+}
+
+impl Deref for UserChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        self.child.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for UserChild {
+    fn deref_mut(&mut self) -> &mut Child {
+        self.child.as_mut().unwrap()
+    }
+}
+
+impl Drop for UserChild {
+    fn drop(&mut self) {
+        let Some(child) = self.child.as_mut() else {
+            return;
+        };
+        if !self.kill_on_drop {
+            return;
+        }
+        // try_wait also returns the status of a child that was already
+        // waited on, without touching the pid again
+        if let Ok(None) = child.try_wait() {
+            if let Err(e) = child.kill() {
+                log::warn!("Failed to kill child {} on drop: {}", child.id(), e);
+                return;
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Spawns `program` with `args` as `user`, returning a handle that kills
+/// the child if it is dropped before being waited on.
+///
+/// The command is set up by `cmd_as_user`. Stdin, stdout and stderr are
+/// inherited; to pipe them or change anything else, build the command
+/// yourself and wrap the spawned child with `UserChild::from`.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured and `CmdError::SpawnFailed` if the command could not be started.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::spawn_as_user;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let mut child = spawn_as_user("Rscript", &["pipeline.R"], &user).unwrap();
+/// // returning early here kills the job instead of leaking it
+/// let status = child.wait().unwrap();
+/// println!("exited with {}", status);
+/// ```
+pub fn spawn_as_user<S: AsRef<OsStr>>(
+    program: impl AsRef<OsStr>,
+    args: &[S],
+    user: &User,
+) -> Result<UserChild, CmdError> {
+    let mut cmd = cmd_as_user(program, user.clone())?;
+    let child = cmd.args(args).spawn().map_err(CmdError::SpawnFailed)?;
+    Ok(UserChild::from(child))
+}
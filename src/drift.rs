@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Display;
+
+use users::User;
+
+use crate::env::{self, get_user_env};
+
+/// Keys that differ between two environments.
+///
+/// Keys are kept sorted so reports are stable from one run to the next.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EnvDiff {
+    /// Keys only present in the current environment.
+    pub added: BTreeSet<String>,
+    /// Keys only present in the baseline.
+    pub removed: BTreeSet<String>,
+    /// Keys present in both with different values, mapped to
+    /// `(baseline value, current value)`.
+    pub changed: BTreeMap<String, (String, String)>,
+}
+
+impl EnvDiff {
+    /// Compares `current` against `baseline`, skipping keys matched by `ignore_keys`.
+    ///
+    /// An ignore entry ending in `*` matches every key starting with the rest
+    /// of it (`SSH_*`); any other entry must match the key exactly.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use polyjuice::EnvDiff;
+    ///
+    /// let env = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+    ///     pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    /// };
+    /// let baseline = env(&[("PATH", "/usr/bin"), ("EDITOR", "vi"), ("PWD", "/")]);
+    /// let current = env(&[("PATH", "/opt/bin:/usr/bin"), ("PAGER", "less"), ("PWD", "/tmp")]);
+    ///
+    /// let diff = EnvDiff::between(&baseline, &current, &["PWD"]);
+    /// assert!(diff.added.contains("PAGER"));
+    /// assert!(diff.removed.contains("EDITOR"));
+    /// assert_eq!(diff.changed["PATH"].1, "/opt/bin:/usr/bin");
+    /// assert_eq!(diff.added.len() + diff.removed.len() + diff.changed.len(), 3);
+    /// ```
+    pub fn between(
+        baseline: &HashMap<String, String>,
+        current: &HashMap<String, String>,
+        ignore_keys: &[&str],
+    ) -> Self {
+        let ignored = |key: &str| {
+            ignore_keys
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => key.starts_with(prefix),
+                    None => key == *pattern,
+                })
+        };
+        let mut diff = EnvDiff::default();
+        for (key, expected) in baseline.iter().filter(|(k, _)| !ignored(k)) {
+            match current.get(key) {
+                None => {
+                    diff.removed.insert(key.clone());
+                }
+                Some(actual) if actual != expected => {
+                    diff.changed
+                        .insert(key.clone(), (expected.clone(), actual.clone()));
+                }
+                Some(_) => {}
+            }
+        }
+        for key in current.keys().filter(|k| !ignored(k)) {
+            if !baseline.contains_key(key) {
+                diff.added.insert(key.clone());
+            }
+        }
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl Display for EnvDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for key in &self.added {
+            writeln!(f, "+ {}", key)?;
+        }
+        for key in &self.removed {
+            writeln!(f, "- {}", key)?;
+        }
+        for (key, (expected, actual)) in &self.changed {
+            writeln!(f, "~ {}: {:?} -> {:?}", key, expected, actual)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum EnvDrift {
+    CaptureFailed(env::Error),
+    Drifted(EnvDiff),
+}
+
+impl Display for EnvDrift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvDrift::CaptureFailed(e) => write!(f, "Failed to get user environment: {}", e),
+            EnvDrift::Drifted(diff) => write!(f, "Environment drifted from baseline:\n{}", diff),
+        }
+    }
+}
+
+/// Captures `user`'s environment and checks it against a stored baseline.
+///
+/// This is meant for scheduled audits: record a user's environment once,
+/// persist it, and later confirm that profile changes have not altered it
+/// unexpectedly. Volatile keys that legitimately change between captures
+/// (`PWD`, `SSH_*`, `OLDPWD`, ...) should be listed in `ignore_keys`; see
+/// `EnvDiff::between` for the pattern syntax.
+///
+/// # Errors
+///
+/// Returns `EnvDrift::Drifted` with the added, removed and changed keys if the
+/// environment differs, or `EnvDrift::CaptureFailed` if it could not be
+/// captured in the first place.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::collections::HashMap;
+/// use polyjuice::{assert_env_matches, EnvDrift};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let baseline: HashMap<String, String> = HashMap::new(); // loaded from disk
+/// match assert_env_matches(&user, &baseline, &["PWD", "OLDPWD", "SSH_*"]) {
+///     Ok(()) => {}
+///     Err(EnvDrift::Drifted(diff)) => eprint!("{}", diff),
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+pub fn assert_env_matches(
+    user: &User,
+    expected: &HashMap<String, String>,
+    ignore_keys: &[&str],
+) -> Result<(), EnvDrift> {
+    let current = get_user_env(user.name()).map_err(EnvDrift::CaptureFailed)?;
+    let diff = EnvDiff::between(expected, current.as_map(), ignore_keys);
+    if diff.is_empty() {
+        Ok(())
+    } else {
+        Err(EnvDrift::Drifted(diff))
+    }
+}
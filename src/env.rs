@@ -1,14 +1,84 @@
 use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Display;
-use std::io;
-use std::process::Command;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 use users::get_effective_uid;
+use users::os::unix::UserExt;
+use users::User;
+
+use crate::lookup::{get_user_by_name_with_timeout, DEFAULT_LOOKUP_TIMEOUT};
+use crate::CmdError;
+
+/// How long a capture may take unless a `CaptureBudget` says otherwise.
+pub const DEFAULT_CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The shell environment capture falls back to when a user's login shell is
+/// missing or not executable.
+pub const DEFAULT_FALLBACK_SHELL: &str = "/bin/sh";
+
+/// Restricted shells, and the unrestricted shell that reads the same startup files.
+const RESTRICTED_SHELLS: &[(&str, &str)] = &[
+    ("rbash", "/bin/bash"),
+    ("rksh", "/bin/ksh"),
+    ("rzsh", "/bin/zsh"),
+];
+
+/// Shells that only refuse the login, such as `/usr/sbin/nologin`.
+const NOLOGIN_SHELLS: &[&str] = &["nologin", "false"];
+
+/// Variables removed from the `su` process during capture.
+///
+/// `su -` normally starts the login shell from a clean environment, keeping
+/// only `TERM`, but not every `su` implementation clears as much. These are
+/// removed so that whatever `su` passes through, the profile cannot mistake
+/// the capture for an interactive session:
+///
+/// - `PS1`, `PS2` and `PROMPT_COMMAND`: prompt settings, whose presence many
+///   rc files test (`[ -n "$PS1" ]`) to decide whether they are interactive;
+/// - `BASH_ENV`: a file bash sources before running a non-interactive
+///   command, which would add startup code the user's login does not run;
+/// - `ENV`: the same for POSIX `sh` and `ksh`.
+///
+/// `TERM` is kept, as `su -` keeps it, so programs launched with the captured
+/// environment still know what terminal they are on.
+///
+/// Besides this, capture runs the shell as
+/// `su - user -c 'env -0 2>/dev/null || printenv'`: a login shell, so the
+/// profile files are read, that is non-interactive (`$-` has no `i`), with
+/// stdin connected to `/dev/null` so `[ -t 0 ]` is false too.
+const INTERACTIVE_VARS: &[&str] = &["PS1", "PS2", "PROMPT_COMMAND", "BASH_ENV", "ENV"];
 
 #[derive(Debug)]
 pub enum Error {
     InsufficientPrivileges,
     FailedExecutingCommand(io::Error),
     CommandExited(String),
+    BudgetExceeded(String),
+    /// Capture did not finish within the given time; holds whatever it had
+    /// written to stderr by then. Also returned, with no stderr, when looking
+    /// up the user's login shell timed out.
+    Timeout(Duration, String),
+    /// The named variable's key or value is not valid UTF-8, so it cannot be
+    /// returned as a `String`; `get_user_env_os` keeps it as is.
+    InvalidUtf8(OsString),
+    /// `EnvBackend::Auto` found none of the supported tools on `PATH`.
+    NoBackendAvailable,
+    /// Capturing through the given backend failed; the inner error says how.
+    BackendFailed(EnvBackend, Box<Error>),
+    /// The user's login shell refuses logins; see `NologinPolicy`.
+    ShellDisallowsLogin(PathBuf),
+    /// The username could be taken for an option or holds characters no
+    /// login name has; see `get_user_env`.
+    InvalidUsername(OsString),
 }
 
 impl Display for Error {
@@ -17,42 +87,1976 @@ impl Display for Error {
             Error::InsufficientPrivileges => write!(f, "Insufficient privileges"),
             Error::FailedExecutingCommand(e) => write!(f, "Failed executing command: {}", e),
             Error::CommandExited(e) => write!(f, "Command exited: {}", e),
+            Error::BudgetExceeded(e) => write!(f, "Capture budget exceeded: {}", e),
+            Error::Timeout(timeout, stderr) if stderr.trim().is_empty() => {
+                write!(f, "Capture timed out after {:?}", timeout)
+            }
+            Error::Timeout(timeout, stderr) => {
+                write!(
+                    f,
+                    "Capture timed out after {:?}: {}",
+                    timeout,
+                    stderr.trim()
+                )
+            }
+            Error::InvalidUtf8(key) => write!(
+                f,
+                "Environment variable {} is not valid UTF-8",
+                key.to_string_lossy()
+            ),
+            Error::NoBackendAvailable => write!(f, "None of su, runuser or sudo found on PATH"),
+            Error::BackendFailed(backend, e) => {
+                write!(f, "Capturing with {} failed: {}", backend, e)
+            }
+            Error::ShellDisallowsLogin(shell) => {
+                write!(f, "Login shell {} does not permit logins", shell.display())
+            }
+            Error::InvalidUsername(name) => write!(f, "Invalid username {:?}", name),
         }
     }
 }
 
-pub fn get_user_env(user: String) -> Result<HashMap<String, String>, Error> {
-    if get_effective_uid() != 0 {
-        return Err(Error::InsufficientPrivileges);
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::FailedExecutingCommand(e) => Some(e),
+            Error::BackendFailed(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
     }
+}
+
+/// What PAM and the NSS backends behind it report when an LDAP or SSSD
+/// server is briefly unreachable.
+const TRANSIENT_MESSAGES: &[&str] = &[
+    // PAM_AUTHINFO_UNAVAIL, from pam_sss or pam_ldap
+    "Authentication service cannot retrieve authentication info",
+    // libldap's LDAP_SERVER_DOWN
+    "Can't contact LDAP server",
+    // nss_ldap
+    "could not connect to any LDAP server",
+];
 
-    // Execute the command and capture the output
-    let output = Command::new("su")
-        .arg("-")
-        .arg(user)
-        .arg("-c")
-        .arg("printenv")
-        .output()
-        .map_err(|e| Error::FailedExecutingCommand(e))?;
+impl Error {
+    /// Whether the capture might succeed if tried again.
+    ///
+    /// That is the case for a capture command that exited unsuccessfully
+    /// after reporting one of the errors a briefly unreachable LDAP or SSSD
+    /// backend shows up as, such as "Authentication service cannot retrieve
+    /// authentication info". Every other failure (a user that does not
+    /// exist, a login profile that exits with an error, a rejected username,
+    /// missing privileges, a shell that refuses logins, ...) is taken to fail
+    /// the same way every time.
+    ///
+    /// A timeout is not transient either: most often the profile is waiting
+    /// on something that will not come, and trying again just waits as long
+    /// again. `RetryPolicy::retry_timeouts` opts into retrying them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::EnvError;
+    ///
+    /// let unreachable = "su: Authentication service cannot retrieve authentication info";
+    /// assert!(EnvError::CommandExited(unreachable.into()).is_transient());
+    /// let unknown = "su: user nobody2 does not exist";
+    /// assert!(!EnvError::CommandExited(unknown.into()).is_transient());
+    /// let profile = "/home/example_user/.bash_profile: line 4: conda: command not found";
+    /// assert!(!EnvError::CommandExited(profile.into()).is_transient());
+    /// assert!(!EnvError::InsufficientPrivileges.is_transient());
+    /// ```
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::CommandExited(stderr) => TRANSIENT_MESSAGES
+                .iter()
+                .any(|message| stderr.contains(message)),
+            Error::BackendFailed(_, e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+/// A user's login environment, as captured by `get_user_env`.
+///
+/// Keys and values are the ones the user's login profile exported. `Display`
+/// prints one `KEY=VALUE` line per variable, sorted by key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserEnv(HashMap<String, String>);
+
+impl UserEnv {
+    /// Parses the output of `env -0` or of `printenv`.
+    ///
+    /// Output containing a NUL byte is taken to be from `env -0`: entries are
+    /// NUL-terminated, so values may contain newlines. Anything else is read
+    /// as `printenv` output, one `KEY=VALUE` per line, where a value with a
+    /// newline cannot be told apart from the start of a new entry and the
+    /// continuation lines are lost. In both formats the key ends at the first
+    /// `=`, so values may contain `=`, and entries without any `=` are
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::InvalidUtf8` if any key or value is not valid UTF-8.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{EnvError, UserEnv};
+    ///
+    /// let env = UserEnv::parse(b"PS1=line one\nline two\0OPTS=a=b\0EMPTY=\0").unwrap();
+    /// assert_eq!(env.get("PS1"), Some("line one\nline two"));
+    /// assert_eq!(env.get("OPTS"), Some("a=b"));
+    /// assert_eq!(env.get("EMPTY"), Some(""));
+    /// assert_eq!(env.iter().count(), 3);
+    ///
+    /// let env = UserEnv::parse(b"OPTS=a=b\nEMPTY=\nnot an entry\n").unwrap();
+    /// assert_eq!(env.get("OPTS"), Some("a=b"));
+    /// assert_eq!(env.get("EMPTY"), Some(""));
+    /// assert_eq!(env.iter().count(), 2);
+    ///
+    /// let latin1 = UserEnv::parse(b"NAME=Jos\xe9\0");
+    /// assert!(matches!(latin1, Err(EnvError::InvalidUtf8(key)) if key == "NAME"));
+    /// ```
+    pub fn parse(output: &[u8]) -> Result<Self, Error> {
+        UserEnv::try_from(UserEnv::parse_os(output))
+    }
+
+    /// Like `parse`, but keeps keys and values as raw bytes instead of
+    /// requiring UTF-8.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::ffi::OsStr;
+    /// use std::os::unix::ffi::OsStrExt;
+    /// use std::process::Command;
+    /// use polyjuice::UserEnv;
+    ///
+    /// let env = UserEnv::parse_os(b"NAME=Jos\xe9\0");
+    /// assert_eq!(env[OsStr::new("NAME")].as_bytes(), b"Jos\xe9");
+    ///
+    /// let seen = Command::new("printenv").arg("NAME").env_clear().envs(&env).output().unwrap();
+    /// assert_eq!(seen.stdout, b"Jos\xe9\n");
+    /// ```
+    pub fn parse_os(output: &[u8]) -> HashMap<OsString, OsString> {
+        let delimiter = match output.contains(&0) {
+            true => 0,
+            false => b'\n',
+        };
+        let mut env_map = HashMap::new();
+        for entry in output.split(|&b| b == delimiter) {
+            if let Some(eq) = entry.iter().position(|&b| b == b'=') {
+                env_map.insert(
+                    OsStr::from_bytes(&entry[..eq]).to_os_string(),
+                    OsStr::from_bytes(&entry[eq + 1..]).to_os_string(),
+                );
+            }
+        }
+        env_map
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// The user's preferred editor: `VISUAL`, else `EDITOR`, else `vi`.
+    ///
+    /// Variables that are set but empty are skipped. The value is a command
+    /// line, not necessarily a path; editors are often given with options,
+    /// such as `code --wait`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::UserEnv;
+    ///
+    /// let env = UserEnv::parse(b"EDITOR=nano\0VISUAL=\0").unwrap();
+    /// assert_eq!(env.editor(), "nano");
+    /// assert_eq!(UserEnv::default().editor(), "vi");
+    /// ```
+    pub fn editor(&self) -> &str {
+        self.first_set(&["VISUAL", "EDITOR"]).unwrap_or("vi")
+    }
+
+    /// The user's preferred pager: `PAGER`, else `less`.
+    ///
+    /// An empty `PAGER` is skipped, as for `editor`.
+    pub fn pager(&self) -> &str {
+        self.first_set(&["PAGER"]).unwrap_or("less")
+    }
+
+    /// The user's shell as their login set it: `SHELL`, else `/bin/sh`, which
+    /// is also what an empty shell field in the passwd entry means.
+    pub fn shell(&self) -> &str {
+        self.first_set(&["SHELL"]).unwrap_or("/bin/sh")
+    }
+
+    /// The value of the first of `keys` that is set and not empty.
+    fn first_set(&self, keys: &[&str]) -> Option<&str> {
+        keys.iter()
+            .filter_map(|key| self.get(key))
+            .find(|value| !value.is_empty())
+    }
+
+    /// Iterates over the variables in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    pub fn as_map(&self) -> &HashMap<String, String> {
+        &self.0
+    }
+
+    pub fn into_map(self) -> HashMap<String, String> {
+        self.0
+    }
+}
+
+impl From<HashMap<String, String>> for UserEnv {
+    fn from(map: HashMap<String, String>) -> Self {
+        UserEnv(map)
+    }
+}
+
+impl TryFrom<HashMap<OsString, OsString>> for UserEnv {
+    type Error = Error;
+
+    /// Fails with `Error::InvalidUtf8` rather than replacing invalid bytes.
+    fn try_from(map: HashMap<OsString, OsString>) -> Result<Self, Self::Error> {
+        map.into_iter()
+            .map(|(key, value)| match (key.to_str(), value.to_str()) {
+                (Some(k), Some(v)) => Ok((k.to_string(), v.to_string())),
+                _ => Err(Error::InvalidUtf8(key)),
+            })
+            .collect::<Result<_, _>>()
+            .map(UserEnv)
+    }
+}
+
+impl Display for UserEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut vars: Vec<_> = self.iter().collect();
+        vars.sort();
+        for (key, value) in vars {
+            writeln!(f, "{}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Captures `user`'s login environment.
+///
+/// The environment is what `env -0` (or, where `env` lacks `-0`, `printenv`)
+/// prints when run through `su - <user>`, so it reflects the user's login
+/// profile. See `UserEnv::parse` for how the output is read. Capturing runs
+/// the whole login stack and can take a while; fetch it once and reuse it
+/// (e.g. with `cmd_as_user_with_env`) when launching many commands as the
+/// same user.
+///
+/// Where `su` is not installed or asks for a password even when run as root,
+/// as on some container images, the capture is retried with `runuser`; a
+/// debug message says which of the two was used.
+///
+/// A profile that hangs, say on a `kinit` waiting for a password, is killed
+/// after `DEFAULT_CAPTURE_TIMEOUT`; use `get_user_env_with_timeout` to wait
+/// longer or less.
+///
+/// # Errors
+///
+/// Returns `Error::InvalidUsername` if `user` is not a plausible login
+/// name, `Error::InsufficientPrivileges` unless running as root,
+/// `Error::FailedExecutingCommand` if `su` could not be run,
+/// `Error::CommandExited` with its stderr if it failed, `Error::Timeout` if
+/// it did not finish in time, and `Error::InvalidUtf8` if a variable is not
+/// valid UTF-8 (use `get_user_env_os` for those).
+///
+/// The name is passed to `su` as an argument of its own, so it cannot reach
+/// a shell, but `su` would still parse a name like `-l` as an option. Names
+/// are therefore checked before anything runs: they must be non-empty, must
+/// not start with `-`, and may only hold ASCII letters, digits, `.`, `_`,
+/// `-` and `@` (for `user@domain` accounts from SSSD or winbind), non-ASCII
+/// bytes, and a trailing `$` (for Samba machine accounts).
+///
+/// # Examples
+///
+/// ```
+/// use polyjuice::{get_user_env, EnvError};
+///
+/// for name in ["-l", "root; rm -rf", "", "a b"] {
+///     assert!(matches!(get_user_env(name), Err(EnvError::InvalidUsername(_))));
+/// }
+/// ```
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_env, get_user_env};
+///
+/// let env = get_user_env("example_user").unwrap();
+/// println!("PATH is {:?}", env.get("PATH"));
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_env("R", &user, env.as_map());
+/// ```
+pub fn get_user_env(user: impl AsRef<OsStr>) -> Result<UserEnv, Error> {
+    get_user_env_os(user).and_then(UserEnv::try_from)
+}
+
+/// How `get_user_env_with_retry_policy` retries a failed capture.
+///
+/// Up to `attempts` captures are made (0 is taken as 1). The first retry
+/// waits `backoff`, and each one after that twice as long as the one before.
+/// Only failures that `is_transient` are retried, and with `retry_timeouts`
+/// timeouts too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub backoff: Duration,
+    pub retry_timeouts: bool,
+}
+
+impl RetryPolicy {
+    fn retries(&self, e: &Error) -> bool {
+        e.is_transient() || (self.retry_timeouts && matches!(e, Error::Timeout(..)))
+    }
+}
+
+/// Like `get_user_env`, but tries up to `attempts` times while the failure
+/// `is_transient`.
+///
+/// This is `get_user_env_with_retry_policy` without retrying timeouts.
+///
+/// # Errors
+///
+/// As for `get_user_env`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::get_user_env_with_retries;
+///
+/// // waits 0.5s, 1s and 2s between the four attempts
+/// let env = get_user_env_with_retries("example_user", 4, Duration::from_millis(500)).unwrap();
+/// ```
+pub fn get_user_env_with_retries(
+    user: impl AsRef<OsStr>,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<UserEnv, Error> {
+    let policy = RetryPolicy {
+        attempts,
+        backoff,
+        retry_timeouts: false,
+    };
+    get_user_env_with_retry_policy(user, &policy)
+}
+
+/// Like `get_user_env`, but retries failed captures as `policy` says.
+///
+/// Each retry is logged at debug level. A failure that is not retried, such
+/// as a user that does not exist or a login profile that fails, is returned
+/// straight away, as is the last failure once the attempts are used up.
+///
+/// # Errors
+///
+/// As for `get_user_env`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{get_user_env_with_retry_policy, RetryPolicy};
+///
+/// // the home directories are on an automounter that is slow to wake up
+/// let policy = RetryPolicy {
+///     attempts: 3,
+///     backoff: Duration::from_secs(1),
+///     retry_timeouts: true,
+/// };
+/// let env = get_user_env_with_retry_policy("example_user", &policy).unwrap();
+/// ```
+pub fn get_user_env_with_retry_policy(
+    user: impl AsRef<OsStr>,
+    policy: &RetryPolicy,
+) -> Result<UserEnv, Error> {
+    let user = user.as_ref();
+    let attempts = policy.attempts;
+    let mut delay = policy.backoff;
+    let mut attempt = 1;
+    loop {
+        match get_user_env(user) {
+            Err(e) if attempt < attempts && policy.retries(&e) => {
+                log::debug!(
+                    "Capturing the environment of {} failed on attempt {} of {}, retrying in {:?}: {}",
+                    user.to_string_lossy(),
+                    attempt,
+                    attempts,
+                    delay,
+                    e
+                );
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Like `get_user_env`, but keeps keys and values as raw bytes.
+///
+/// Profiles are free to export values in any encoding (a latin-1 locale
+/// setting, say), and `Command::envs` passes such values on unchanged. This
+/// is what the `cmd_as_user` family uses, so those values reach the child
+/// exactly as the profile exported them.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::ffi::OsStr;
+/// use polyjuice::get_user_env_os;
+///
+/// let env = get_user_env_os("example_user").unwrap();
+/// let lang = env.get(OsStr::new("LANG"));
+/// ```
+pub fn get_user_env_os(user: impl AsRef<OsStr>) -> Result<HashMap<OsString, OsString>, Error> {
+    get_user_env_os_with_fallback_shell(user.as_ref(), Path::new(DEFAULT_FALLBACK_SHELL))
+}
+
+/// Whether `UserEnvCache::get_or_fetch` answered from the cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheOutcome {
+    Hit,
+    /// Nothing was cached for the user; the environment was fetched.
+    Miss,
+    /// The cached environment was older than the TTL and was fetched again.
+    Expired,
+}
+
+type Fetcher = dyn Fn(&str) -> Result<UserEnv, Error> + Send + Sync;
+
+/// Memoizes captured environments per username.
+///
+/// Capturing runs the user's whole login stack, so services launching many
+/// commands for the same users pay for it over and over. The cache keeps
+/// each user's environment for `ttl` and transparently fetches it again
+/// once it is older. At most `max_entries` users are kept; adding one more
+/// evicts the one fetched longest ago.
+///
+/// The cache is used through `&self` and can be shared between threads.
+/// Fetching happens outside the lock, so a slow capture for one user does
+/// not hold up lookups for others. Two threads missing on the same user at
+/// the same moment may both fetch, and the later result is kept.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{CacheOutcome, UserEnvCache};
+///
+/// let cache = UserEnvCache::new(Duration::from_secs(300), 100);
+/// let (env, outcome) = cache.get_or_fetch("example_user").unwrap();
+/// if outcome != CacheOutcome::Hit {
+///     println!("captured environment for example_user");
+/// }
+/// ```
+pub struct UserEnvCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<String, (Instant, UserEnv)>>,
+    fetch: Box<Fetcher>,
+}
+
+impl std::fmt::Debug for UserEnvCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserEnvCache")
+            .field("ttl", &self.ttl)
+            .field("max_entries", &self.max_entries)
+            .finish_non_exhaustive()
+    }
+}
 
-    // Check for command execution errors
-    if !output.status.success() {
+impl UserEnvCache {
+    /// A cache that captures missing environments with `get_user_env`.
+    pub fn new(ttl: Duration, max_entries: usize) -> Self {
+        Self::with_fetcher(ttl, max_entries, |user| get_user_env(user))
+    }
+
+    /// A cache that fetches missing environments with `fetch`, e.g. one of
+    /// the other capture functions or a stub in tests.
+    ///
+    /// # Examples
+    ///
+    /// Entries are refetched once they outlive the TTL:
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use polyjuice::{CacheOutcome, UserEnv, UserEnvCache};
+    ///
+    /// let cache = UserEnvCache::with_fetcher(Duration::from_millis(50), 10, |_| Ok(UserEnv::default()));
+    /// assert_eq!(cache.get_or_fetch("alice").unwrap().1, CacheOutcome::Miss);
+    /// assert_eq!(cache.get_or_fetch("alice").unwrap().1, CacheOutcome::Hit);
+    /// std::thread::sleep(Duration::from_millis(60));
+    /// assert_eq!(cache.get_or_fetch("alice").unwrap().1, CacheOutcome::Expired);
+    /// ```
+    ///
+    /// and can be shared between threads:
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::time::Duration;
+    /// use polyjuice::{UserEnv, UserEnvCache};
+    ///
+    /// static FETCHES: AtomicUsize = AtomicUsize::new(0);
+    /// let cache = UserEnvCache::with_fetcher(Duration::from_secs(60), 10, |user| {
+    ///     FETCHES.fetch_add(1, Ordering::SeqCst);
+    ///     let env = HashMap::from([("USER".to_string(), user.to_string())]);
+    ///     Ok(UserEnv::from(env))
+    /// });
+    ///
+    /// cache.get_or_fetch("alice").unwrap();
+    /// std::thread::scope(|scope| {
+    ///     for _ in 0..4 {
+    ///         scope.spawn(|| {
+    ///             for user in ["alice", "bob"] {
+    ///                 let (env, _) = cache.get_or_fetch(user).unwrap();
+    ///                 assert_eq!(env.get("USER"), Some(user));
+    ///             }
+    ///         });
+    ///     }
+    /// });
+    /// // alice was cached up front; bob is fetched by at least one thread
+    /// assert!((2..=5).contains(&FETCHES.load(Ordering::SeqCst)));
+    /// ```
+    pub fn with_fetcher(
+        ttl: Duration,
+        max_entries: usize,
+        fetch: impl Fn(&str) -> Result<UserEnv, Error> + Send + Sync + 'static,
+    ) -> Self {
+        UserEnvCache {
+            ttl,
+            max_entries,
+            entries: Mutex::new(HashMap::new()),
+            fetch: Box::new(fetch),
+        }
+    }
+
+    /// Returns `username`'s environment, fetching it if it is not cached or
+    /// has expired, along with which of those happened.
+    ///
+    /// # Errors
+    ///
+    /// Returns the fetcher's error if a fetch was needed and failed. Nothing
+    /// is cached in that case, and an expired entry is dropped.
+    pub fn get_or_fetch(&self, username: &str) -> Result<(UserEnv, CacheOutcome), Error> {
+        let outcome = {
+            let mut entries = self.lock();
+            match entries.get(username) {
+                Some((fetched, env)) if fetched.elapsed() < self.ttl => {
+                    return Ok((env.clone(), CacheOutcome::Hit));
+                }
+                Some(_) => {
+                    entries.remove(username);
+                    CacheOutcome::Expired
+                }
+                None => CacheOutcome::Miss,
+            }
+        };
+
+        let env = (self.fetch)(username)?;
+        if self.max_entries > 0 {
+            let mut entries = self.lock();
+            if !entries.contains_key(username) && entries.len() >= self.max_entries {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, (fetched, _))| *fetched)
+                    .map(|(name, _)| name.clone());
+                if let Some(oldest) = oldest {
+                    entries.remove(&oldest);
+                }
+            }
+            entries.insert(username.to_string(), (Instant::now(), env.clone()));
+        }
+        Ok((env, outcome))
+    }
+
+    /// Drops `username`'s cached environment, so the next lookup fetches it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use polyjuice::{CacheOutcome, UserEnv, UserEnvCache};
+    ///
+    /// let cache = UserEnvCache::with_fetcher(Duration::from_secs(60), 10, |_| Ok(UserEnv::default()));
+    /// cache.get_or_fetch("alice").unwrap();
+    /// cache.get_or_fetch("bob").unwrap();
+    ///
+    /// cache.invalidate("alice");
+    /// assert_eq!(cache.get_or_fetch("alice").unwrap().1, CacheOutcome::Miss);
+    /// assert_eq!(cache.get_or_fetch("bob").unwrap().1, CacheOutcome::Hit);
+    ///
+    /// cache.clear();
+    /// assert_eq!(cache.get_or_fetch("bob").unwrap().1, CacheOutcome::Miss);
+    /// ```
+    pub fn invalidate(&self, username: &str) {
+        self.lock().remove(username);
+    }
+
+    /// Drops every cached environment.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, (Instant, UserEnv)>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Like `get_user_env`, capturing with `fallback` instead of the user's login
+/// shell if that does not exist or is not executable.
+///
+/// `su -` cannot start a user whose shell field is broken, which is common
+/// for migrated and service accounts. Such entries are instead captured
+/// with `su -s <fallback>`, which still reads the profile files `fallback`
+/// reads, and a warning is logged. `SHELL` in the captured environment is
+/// then `fallback` too, since that is the shell that actually works.
+///
+/// `get_user_env` does the same with `DEFAULT_FALLBACK_SHELL`.
+///
+/// # Errors
+///
+/// As for `get_user_env`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::get_user_env_with_fallback_shell;
+///
+/// let env = get_user_env_with_fallback_shell("migrated_user", "/bin/bash").unwrap();
+/// ```
+pub fn get_user_env_with_fallback_shell(
+    user: impl AsRef<OsStr>,
+    fallback: impl AsRef<Path>,
+) -> Result<UserEnv, Error> {
+    get_user_env_os_with_fallback_shell(user.as_ref(), fallback.as_ref())
+        .and_then(UserEnv::try_from)
+}
+
+/// Like `get_user_env_with_fallback_shell`, keeping raw bytes as
+/// `get_user_env_os` does.
+pub(crate) fn get_user_env_os_with_fallback_shell(
+    user: &OsStr,
+    fallback: &Path,
+) -> Result<HashMap<OsString, OsString>, Error> {
+    CaptureOptions {
+        fallback_shell: fallback.to_path_buf(),
+        ..CaptureOptions::default()
+    }
+    .capture(user)
+}
+
+/// Captures with `su`, retrying with `runuser` if `su` is not installed or
+/// asks for a password, as it does on some container images even for root.
+///
+/// If `runuser` fails as well, the error from `su` is returned.
+fn capture_with_su(
+    user: &OsStr,
+    fallback: &Path,
+    nologin: NologinPolicy,
+    run: impl Fn(Command, &Capture) -> Result<HashMap<OsString, OsString>, Error>,
+) -> Result<HashMap<OsString, OsString>, Error> {
+    su_then_runuser(
+        user,
+        |backend| Capture::new(user, fallback, backend, nologin),
+        run,
+    )
+}
+
+/// `capture_with_su`, building each backend's capture with `capture`.
+fn su_then_runuser(
+    user: &OsStr,
+    capture: impl Fn(EnvBackend) -> Result<(Command, Capture), Error>,
+    run: impl Fn(Command, &Capture) -> Result<HashMap<OsString, OsString>, Error>,
+) -> Result<HashMap<OsString, OsString>, Error> {
+    let (cmd, su_capture) = capture(EnvBackend::Su)?;
+    let su_error = match run(cmd, &su_capture) {
+        Ok(env) => {
+            log::debug!(
+                "Captured the environment of {} with su",
+                user.to_string_lossy()
+            );
+            return Ok(env);
+        }
+        Err(e) if su_unusable(&e) => e,
+        Err(e) => return Err(e),
+    };
+    log::debug!("su is unusable ({}), trying runuser", su_error);
+    let (cmd, runuser_capture) = capture(EnvBackend::Runuser)?;
+    match run(cmd, &runuser_capture) {
+        Ok(env) => {
+            log::debug!(
+                "Captured the environment of {} with runuser",
+                user.to_string_lossy()
+            );
+            Ok(env)
+        }
+        Err(e) => {
+            log::debug!("runuser failed too: {}", e);
+            Err(su_error)
+        }
+    }
+}
+
+/// What `su` itself prints when PAM wanted a password it did not get.
+const SU_AUTH_FAILURES: &[&str] = &[
+    "su: Authentication failure",
+    "su: incorrect password",
+    "su: Sorry",
+];
+
+/// Whether `e` says `su` is missing or wanted to authenticate, rather than
+/// that the user's login failed.
+///
+/// Only `su`'s own messages count, so a profile that happens to print
+/// "password" does not send the capture to `runuser`. The `Password:`
+/// prompt that precedes them on the same line is skipped.
+fn su_unusable(e: &Error) -> bool {
+    match e {
+        Error::FailedExecutingCommand(e) => e.kind() == io::ErrorKind::NotFound,
+        Error::CommandExited(stderr) => stderr
+            .lines()
+            .map(|line| line.trim_start_matches("Password:").trim())
+            .any(|line| SU_AUTH_FAILURES.contains(&line)),
+        _ => false,
+    }
+}
+
+/// What capture does for a user whose login shell refuses logins.
+///
+/// Service accounts commonly have `/usr/sbin/nologin` or `/bin/false` as
+/// their shell, which would simply exit instead of printing an environment.
+///
+/// - `Reject` fails with `Error::ShellDisallowsLogin` without running
+///   anything; the account was deliberately closed to logins.
+/// - `Fallback` captures with `DEFAULT_FALLBACK_SHELL` instead, as is done
+///   for a missing shell, and logs a warning. `sudo` cannot be told which
+///   shell to use, so with `EnvBackend::Sudo` this still fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NologinPolicy {
+    #[default]
+    Reject,
+    Fallback,
+}
+
+/// Like `get_user_env`, handling a non-login shell according to `policy`.
+///
+/// # Errors
+///
+/// As for `get_user_env`, plus `Error::ShellDisallowsLogin` under
+/// `NologinPolicy::Reject`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{get_user_env_with_nologin_policy, NologinPolicy};
+///
+/// let env = get_user_env_with_nologin_policy("www-data", NologinPolicy::Fallback).unwrap();
+/// ```
+pub fn get_user_env_with_nologin_policy(
+    user: impl AsRef<OsStr>,
+    policy: NologinPolicy,
+) -> Result<UserEnv, Error> {
+    get_user_env_os_with_nologin_policy(user.as_ref(), policy).and_then(UserEnv::try_from)
+}
+
+/// Like `get_user_env_with_nologin_policy`, keeping raw bytes as
+/// `get_user_env_os` does.
+pub(crate) fn get_user_env_os_with_nologin_policy(
+    user: &OsStr,
+    policy: NologinPolicy,
+) -> Result<HashMap<OsString, OsString>, Error> {
+    CaptureOptions {
+        nologin: policy,
+        ..CaptureOptions::default()
+    }
+    .capture(user)
+}
+
+/// The shell to start for an interactive session of `user`: their login
+/// shell, or `DEFAULT_FALLBACK_SHELL` where capture would fall back to it.
+///
+/// Unlike capture, a restricted shell is kept as it is, since here it is
+/// the user who types the commands.
+pub(crate) fn interactive_shell(user: &User, nologin: NologinPolicy) -> Result<PathBuf, Error> {
+    let fallback = Path::new(DEFAULT_FALLBACK_SHELL);
+    let shell = match capture_shell(Some(user.shell()), fallback, nologin)? {
+        CaptureShell::Login(shell) => shell,
+        CaptureShell::Unrestricted(_) => user.shell().to_path_buf(),
+        CaptureShell::Default | CaptureShell::Invalid(_) | CaptureShell::Nologin(_) => {
+            fallback.to_path_buf()
+        }
+    };
+    Ok(shell)
+}
+
+/// The tool environment capture runs the login shell through.
+///
+/// All three start a login shell for the user and print its environment,
+/// but they differ in what they need and what they do along the way:
+///
+/// - `Su` runs `su - <user>`. It goes through the `su` PAM stack, which on
+///   some systems includes modules that prompt and would hang the capture.
+/// - `Runuser` runs `runuser -l <user>`. It is util-linux's `su` for root,
+///   without the interactive authentication part of PAM.
+/// - `Sudo` runs `sudo -n -i -u <user>`; `-n` makes sudo fail instead of
+///   prompting. sudo always starts the shell from the passwd entry, so
+///   restricted or broken login shells are not worked around as they are
+///   with the other two, and the `SUDO_*` variables sudo adds are removed
+///   from the result.
+/// - `Auto` picks the first of `runuser`, `su` and `sudo` found on `PATH`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnvBackend {
+    #[default]
+    Su,
+    Runuser,
+    Sudo,
+    Auto,
+}
+
+impl EnvBackend {
+    fn program(self) -> &'static str {
+        match self {
+            EnvBackend::Su | EnvBackend::Auto => "su",
+            EnvBackend::Runuser => "runuser",
+            EnvBackend::Sudo => "sudo",
+        }
+    }
+
+    /// Replaces `Auto` with the first backend whose tool is on `PATH`.
+    fn resolve(self) -> Result<Self, Error> {
+        if self != EnvBackend::Auto {
+            return Ok(self);
+        }
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        [EnvBackend::Runuser, EnvBackend::Su, EnvBackend::Sudo]
+            .into_iter()
+            .find(|backend| {
+                std::env::split_paths(&path).any(|dir| is_executable(&dir.join(backend.program())))
+            })
+            .ok_or(Error::NoBackendAvailable)
+    }
+}
+
+impl Display for EnvBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EnvBackend::Auto => write!(f, "auto"),
+            backend => write!(f, "{}", backend.program()),
+        }
+    }
+}
+
+/// Like `get_user_env`, capturing through `backend` instead of `su`.
+///
+/// # Errors
+///
+/// Returns `Error::NoBackendAvailable` if `backend` is `EnvBackend::Auto` and
+/// no tool was found, and `Error::BackendFailed` naming the backend if it
+/// could not be run (e.g. `runuser` is not installed) or exited with an error.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{get_user_env_with_backend, EnvBackend};
+///
+/// let env = get_user_env_with_backend("example_user", EnvBackend::Auto).unwrap();
+/// ```
+pub fn get_user_env_with_backend(
+    user: impl AsRef<OsStr>,
+    backend: EnvBackend,
+) -> Result<UserEnv, Error> {
+    get_user_env_os_with_backend(user.as_ref(), backend).and_then(UserEnv::try_from)
+}
+
+/// Like `get_user_env_with_backend`, keeping raw bytes as `get_user_env_os` does.
+pub(crate) fn get_user_env_os_with_backend(
+    user: &OsStr,
+    backend: EnvBackend,
+) -> Result<HashMap<OsString, OsString>, Error> {
+    CaptureOptions {
+        backend: Some(backend),
+        ..CaptureOptions::default()
+    }
+    .capture(user)
+}
+
+/// Everything that can be tuned about how an environment is captured, as
+/// set on `CommandBuilder`.
+#[derive(Debug, Clone)]
+pub(crate) struct CaptureOptions {
+    /// `None` captures with `su`, falling back to `runuser` where `su` is
+    /// unusable; a backend set explicitly is used on its own.
+    pub(crate) backend: Option<EnvBackend>,
+    pub(crate) fallback_shell: PathBuf,
+    pub(crate) nologin: NologinPolicy,
+    pub(crate) budget: CaptureBudget,
+}
+
+impl Default for CaptureOptions {
+    /// What `get_user_env_os` does.
+    fn default() -> Self {
+        CaptureOptions {
+            backend: None,
+            fallback_shell: PathBuf::from(DEFAULT_FALLBACK_SHELL),
+            nologin: NologinPolicy::default(),
+            budget: default_budget(),
+        }
+    }
+}
+
+impl CaptureOptions {
+    /// Captures the environment of `user`, keeping raw bytes.
+    pub(crate) fn capture(&self, user: &OsStr) -> Result<HashMap<OsString, OsString>, Error> {
+        let run = |cmd, capture: &Capture| run_capture_with_budget(cmd, capture, &self.budget);
+        let Some(backend) = self.backend else {
+            return capture_with_su(user, &self.fallback_shell, self.nologin, run);
+        };
+        let backend = backend.resolve()?;
+        let (cmd, capture) = Capture::new(user, &self.fallback_shell, backend, self.nologin)?;
+        run(cmd, &capture).map_err(|e| match e {
+            Error::FailedExecutingCommand(_) | Error::CommandExited(_) => {
+                Error::BackendFailed(backend, Box::new(e))
+            }
+            e => e,
+        })
+    }
+}
+
+/// The placeholder `EnvSource::Custom` replaces with the user name.
+pub const USER_PLACEHOLDER: &str = "{user}";
+
+/// Where environment capture gets its output from.
+///
+/// `Backend` runs one of the built-in tools, with the handling for
+/// restricted and broken login shells described on `EnvBackend`. `Custom`
+/// runs a caller-supplied argv instead, with every `{user}` in it (see
+/// `USER_PLACEHOLDER`) replaced by the user name, e.g. to capture through
+/// `machinectl shell` or a site-specific wrapper. Its output is read like
+/// that of the built-in command, so it must print `KEY=VALUE` entries
+/// separated by NUL or newline, and it runs with the caller's privileges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvSource {
+    Backend(EnvBackend),
+    Custom(Vec<String>),
+}
+
+impl Default for EnvSource {
+    fn default() -> Self {
+        EnvSource::Backend(EnvBackend::default())
+    }
+}
+
+impl From<EnvBackend> for EnvSource {
+    fn from(backend: EnvBackend) -> Self {
+        EnvSource::Backend(backend)
+    }
+}
+
+impl EnvSource {
+    /// Builds the command that would capture `user`'s environment, without
+    /// running it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::NoBackendAvailable` if `EnvBackend::Auto` finds no
+    /// tool, and `Error::FailedExecutingCommand` for an empty `Custom` argv.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{EnvBackend, EnvSource};
+    ///
+    /// let argv = |source: EnvSource| {
+    ///     let cmd = source.command("example_user").unwrap();
+    ///     let mut argv = vec![cmd.get_program().to_owned()];
+    ///     argv.extend(cmd.get_args().map(|arg| arg.to_owned()));
+    ///     argv
+    /// };
+    ///
+    /// assert_eq!(
+    ///     argv(EnvSource::Backend(EnvBackend::Su)),
+    ///     ["su", "-", "example_user", "-c", "env -0 2>/dev/null || printenv"],
+    /// );
+    /// assert_eq!(
+    ///     argv(EnvSource::Backend(EnvBackend::Runuser)),
+    ///     ["runuser", "-l", "example_user", "-c", "env -0 2>/dev/null || printenv"],
+    /// );
+    /// assert_eq!(
+    ///     argv(EnvSource::Backend(EnvBackend::Sudo)),
+    ///     ["sudo", "-n", "-i", "-u", "example_user", "env", "-0"],
+    /// );
+    ///
+    /// let custom = ["machinectl", "shell", "{user}@", "/usr/bin/env", "-0"];
+    /// assert_eq!(
+    ///     argv(EnvSource::Custom(custom.map(String::from).to_vec())),
+    ///     ["machinectl", "shell", "example_user@", "/usr/bin/env", "-0"],
+    /// );
+    /// ```
+    pub fn command(&self, user: impl AsRef<OsStr>) -> Result<Command, Error> {
+        Ok(self.capture(user.as_ref())?.0)
+    }
+
+    fn capture(&self, user: &OsStr) -> Result<(Command, Capture), Error> {
+        validate_username(user)?;
+        match self {
+            EnvSource::Backend(backend) => Capture::build(
+                user,
+                Path::new(DEFAULT_FALLBACK_SHELL),
+                backend.resolve()?,
+                NologinPolicy::default(),
+            ),
+            EnvSource::Custom(argv) => Capture::custom(argv, user),
+        }
+    }
+}
+
+/// Like `get_user_env`, capturing from `source` instead of `su`.
+///
+/// # Errors
+///
+/// For `EnvSource::Backend`, as for `get_user_env_with_backend`. For
+/// `EnvSource::Custom`, `Error::FailedExecutingCommand` if the command could
+/// not be run and `Error::CommandExited` if it failed; root is not required.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{get_user_env_with_source, EnvSource};
+///
+/// let source = EnvSource::Custom(vec![
+///     "/usr/local/sbin/site-env".into(),
+///     "--user".into(),
+///     "{user}".into(),
+/// ]);
+/// let env = get_user_env_with_source("example_user", &source).unwrap();
+/// ```
+pub fn get_user_env_with_source(
+    user: impl AsRef<OsStr>,
+    source: &EnvSource,
+) -> Result<UserEnv, Error> {
+    match source {
+        EnvSource::Backend(backend) => get_user_env_with_backend(user, *backend),
+        EnvSource::Custom(_) => {
+            let (cmd, capture) = source.capture(user.as_ref())?;
+            run_capture(cmd, &capture).and_then(UserEnv::try_from)
+        }
+    }
+}
+
+/// Like `get_user_env`, but awaits the capture instead of blocking on it.
+///
+/// The capture, including the lookup of the user's login shell and the
+/// fallback from `su` to `runuser`, runs on tokio's blocking pool, so the
+/// executor threads are never held up by a slow profile or NSS. Must be
+/// called within a tokio runtime. Dropping the future does not stop a
+/// capture already running; it still ends within its time limit.
+///
+/// # Errors
+///
+/// As for `get_user_env`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example() {
+/// let env = polyjuice::get_user_env_async("example_user").await.unwrap();
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+pub async fn get_user_env_async(user: impl AsRef<OsStr>) -> Result<UserEnv, Error> {
+    get_user_env_async_with_budget(user, &default_budget()).await
+}
+
+/// Like `get_user_env_async`, but bounds the capture by `budget` as
+/// `get_user_env_with_budget` does.
+///
+/// # Errors
+///
+/// As for `get_user_env_with_budget`.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async fn example() {
+/// use std::time::Duration;
+/// use polyjuice::{get_user_env_async_with_budget, BudgetPolicy, CaptureBudget};
+///
+/// let budget = CaptureBudget {
+///     max_duration: Duration::from_secs(5),
+///     max_bytes: 256 * 1024,
+///     on_exceeded: BudgetPolicy::Fail,
+/// };
+/// let env = get_user_env_async_with_budget("example_user", &budget).await.unwrap();
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+pub async fn get_user_env_async_with_budget(
+    user: impl AsRef<OsStr>,
+    budget: &CaptureBudget,
+) -> Result<UserEnv, Error> {
+    let options = CaptureOptions {
+        budget: *budget,
+        ..CaptureOptions::default()
+    };
+    let user = user.as_ref().to_os_string();
+    let captured = tokio::task::spawn_blocking(move || options.capture(&user)).await;
+    match captured {
+        Ok(env) => env.and_then(UserEnv::try_from),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Runs a capture, giving up after `DEFAULT_CAPTURE_TIMEOUT`.
+fn run_capture(cmd: Command, capture: &Capture) -> Result<HashMap<OsString, OsString>, Error> {
+    run_capture_with_budget(cmd, capture, &default_budget())
+}
+
+/// The budget of a capture that was given none: `DEFAULT_CAPTURE_TIMEOUT`
+/// and no limit on output.
+fn default_budget() -> CaptureBudget {
+    CaptureBudget {
+        max_duration: DEFAULT_CAPTURE_TIMEOUT,
+        max_bytes: usize::MAX,
+        on_exceeded: BudgetPolicy::Fail,
+    }
+}
+
+/// How the environment of a launched command combines the captured user
+/// environment with the calling process's own.
+///
+/// - `Replace` drops the caller's environment; the child sees only the
+///   user's. This is what `cmd_as_user` does.
+/// - `InheritCaller` keeps the caller's environment and ignores the user's;
+///   no capture is done at all.
+/// - `MergePreferUser` starts from the caller's environment and overlays the
+///   user's, so the user's value wins where both set a variable.
+/// - `MergePreferCaller` starts from the user's environment and overlays the
+///   caller's, so settings the caller made deliberately (a job's
+///   `OMP_NUM_THREADS`, a proxy) survive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnvPolicy {
+    #[default]
+    Replace,
+    InheritCaller,
+    MergePreferUser,
+    MergePreferCaller,
+}
+
+impl EnvPolicy {
+    /// Whether the user's environment plays any part under this policy.
+    pub(crate) fn needs_capture(self) -> bool {
+        self != EnvPolicy::InheritCaller
+    }
+
+    /// Combines `user_env` with the current process environment.
+    pub(crate) fn apply(
+        self,
+        user_env: HashMap<OsString, OsString>,
+    ) -> HashMap<OsString, OsString> {
+        match self {
+            EnvPolicy::Replace => user_env,
+            EnvPolicy::InheritCaller => std::env::vars_os().collect(),
+            EnvPolicy::MergePreferUser => {
+                let mut env: HashMap<_, _> = std::env::vars_os().collect();
+                env.extend(user_env);
+                env
+            }
+            EnvPolicy::MergePreferCaller => {
+                let mut env = user_env;
+                env.extend(std::env::vars_os());
+                env
+            }
+        }
+    }
+}
+
+/// Limits on how long environment capture may run and how much it may print.
+///
+/// Capturing runs the user's login profile, and a profile that loops,
+/// re-execs itself or dumps large amounts of output would otherwise stall or
+/// bloat the capture indefinitely. The budget bounds the whole capture: the
+/// wall-clock time from starting `su` until it has exited and closed its
+/// output, and the number of bytes read from its stdout and stderr combined.
+/// Captures that take no budget are still bounded by
+/// `DEFAULT_CAPTURE_TIMEOUT`.
+///
+/// When either limit is hit mid-capture, the `su` process and everything it
+/// started in its process group are killed with `SIGKILL`. What follows
+/// depends on `on_exceeded`:
+///
+/// - `BudgetPolicy::Fail` returns `Error::Timeout`, with the stderr written
+///   so far, if time ran out and `Error::BudgetExceeded` if output did;
+/// - `BudgetPolicy::Partial` logs a warning and returns every complete
+///   `KEY=VALUE` entry read up to that point. A trailing entry cut off by the
+///   limit is dropped rather than returned with a truncated value, and so
+///   are variables the profile had yet to print.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureBudget {
+    pub max_duration: Duration,
+    pub max_bytes: usize,
+    pub on_exceeded: BudgetPolicy,
+}
+
+impl Default for CaptureBudget {
+    /// 30 seconds and 1 MiB, failing when exceeded.
+    fn default() -> Self {
+        CaptureBudget {
+            max_duration: Duration::from_secs(30),
+            max_bytes: 1024 * 1024,
+            on_exceeded: BudgetPolicy::Fail,
+        }
+    }
+}
+
+/// What environment capture does when its `CaptureBudget` runs out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetPolicy {
+    #[default]
+    Fail,
+    Partial,
+}
+
+/// Like `get_user_env`, but stops the capture once `budget` is used up.
+///
+/// See `CaptureBudget` for what happens when a limit is hit.
+///
+/// # Errors
+///
+/// Under `BudgetPolicy::Fail`, returns `Error::Timeout` or
+/// `Error::BudgetExceeded` when a limit is hit, and otherwise the same errors
+/// as `get_user_env`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{get_user_env_with_budget, BudgetPolicy, CaptureBudget};
+///
+/// let budget = CaptureBudget {
+///     max_duration: Duration::from_secs(5),
+///     max_bytes: 256 * 1024,
+///     on_exceeded: BudgetPolicy::Partial,
+/// };
+/// let env = get_user_env_with_budget("example_user", &budget).unwrap();
+/// ```
+pub fn get_user_env_with_budget(
+    user: impl AsRef<OsStr>,
+    budget: &CaptureBudget,
+) -> Result<UserEnv, Error> {
+    get_user_env_os_with_budget(user.as_ref(), budget).and_then(UserEnv::try_from)
+}
+
+/// Like `get_user_env_with_budget`, keeping raw bytes as `get_user_env_os` does.
+pub(crate) fn get_user_env_os_with_budget(
+    user: &OsStr,
+    budget: &CaptureBudget,
+) -> Result<HashMap<OsString, OsString>, Error> {
+    CaptureOptions {
+        budget: *budget,
+        ..CaptureOptions::default()
+    }
+    .capture(user)
+}
+
+fn run_capture_with_budget(
+    mut cmd: Command,
+    capture: &Capture,
+    budget: &CaptureBudget,
+) -> Result<HashMap<OsString, OsString>, Error> {
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // so a limit can take down everything the profile started
+        .process_group(0)
+        .spawn()
+        .map_err(Error::FailedExecutingCommand)?;
+    let pid = child.id() as libc::pid_t;
+
+    let (tx, chunks) = mpsc::channel();
+    read_chunks(
+        child.stdout.take().expect("stdout is piped"),
+        true,
+        tx.clone(),
+    );
+    read_chunks(child.stderr.take().expect("stderr is piped"), false, tx);
+    // a deadline too far out to represent is as good as none
+    let deadline = Instant::now().checked_add(budget.max_duration);
+    let remaining = || {
+        deadline.map_or(Duration::MAX, |d| {
+            d.saturating_duration_since(Instant::now())
+        })
+    };
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut timed_out = false;
+    let finished = loop {
+        match chunks.recv_timeout(remaining()) {
+            Ok((is_stdout, chunk)) => {
+                match is_stdout {
+                    true => stdout.extend_from_slice(&chunk),
+                    false => stderr.extend_from_slice(&chunk),
+                }
+                if stdout.len() + stderr.len() > budget.max_bytes {
+                    break Err(format!("output exceeded {} bytes", budget.max_bytes));
+                }
+            }
+            // both streams are closed, but su may not have exited yet
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                if let Some(status) =
+                    wait_until(&mut child, deadline).map_err(Error::FailedExecutingCommand)?
+                {
+                    break Ok(status);
+                }
+                timed_out = true;
+                break Err(format!("capture exceeded {:?}", budget.max_duration));
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                timed_out = true;
+                break Err(format!("capture exceeded {:?}", budget.max_duration));
+            }
+        }
+    };
+
+    let status = match finished {
+        Ok(status) => status,
+        Err(reason) => {
+            kill_capture(pid);
+            let _ = child.wait();
+            return match budget.on_exceeded {
+                BudgetPolicy::Fail if timed_out => {
+                    stderr.extend(drain(&chunks));
+                    Err(Error::Timeout(
+                        budget.max_duration,
+                        String::from_utf8_lossy(&stderr).to_string(),
+                    ))
+                }
+                BudgetPolicy::Fail => Err(Error::BudgetExceeded(reason)),
+                BudgetPolicy::Partial => {
+                    log::warn!(
+                        "Environment capture for {} stopped early ({}), using partial environment",
+                        capture.user.to_string_lossy(),
+                        reason
+                    );
+                    stdout.truncate(budget.max_bytes.saturating_sub(stderr.len()));
+                    let delimiter = match stdout.contains(&0) {
+                        true => 0,
+                        false => b'\n',
+                    };
+                    let complete = stdout
+                        .iter()
+                        .rposition(|&b| b == delimiter)
+                        .map_or(0, |i| i + 1);
+                    Ok(capture.parse(&stdout[..complete]))
+                }
+            };
+        }
+    };
+    if !status.success() {
         return Err(Error::CommandExited(
-            String::from_utf8_lossy(&output.stderr).to_string(),
+            String::from_utf8_lossy(&stderr).to_string(),
+        ));
+    }
+    Ok(capture.parse(&stdout))
+}
+
+/// Like `get_user_env`, but gives up once `timeout` has passed.
+///
+/// A login profile that hangs (waiting on input, or on a stalled NSS
+/// lookup) would otherwise block the capture forever. On timeout the `su`
+/// process and everything it started are killed. For a limit on output size
+/// as well, or to accept a partial environment, see `CaptureBudget`.
+///
+/// # Errors
+///
+/// Returns `Error::Timeout` with the stderr written so far if the capture did
+/// not finish in time, and otherwise the same errors as `get_user_env`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{get_user_env_with_timeout, EnvError};
+///
+/// match get_user_env_with_timeout("example_user", Duration::from_secs(5)) {
+///     Ok(env) => println!("{}", env),
+///     Err(EnvError::Timeout(_, stderr)) => eprintln!("login profile is hanging: {}", stderr),
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+pub fn get_user_env_with_timeout(
+    user: impl AsRef<OsStr>,
+    timeout: Duration,
+) -> Result<UserEnv, Error> {
+    let budget = CaptureBudget {
+        max_duration: timeout,
+        max_bytes: usize::MAX,
+        on_exceeded: BudgetPolicy::Fail,
+    };
+    get_user_env_with_budget(user, &budget)
+}
+
+/// Kills a capture started in its own process group as `pid`, along with
+/// everything it started.
+///
+/// Killing the group alone is not enough: `su` runs the login shell in a new
+/// session, so the shell and whatever the profile is stuck on would survive
+/// it. The descendants of `pid` are therefore looked up in `/proc` first and
+/// each of their process groups is killed as well.
+fn kill_capture(pid: libc::pid_t) {
+    let mut groups = vec![pid];
+    let mut parents = vec![pid];
+    let procs: Vec<(libc::pid_t, libc::pid_t, libc::pid_t)> = fs::read_dir("/proc")
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let stat = fs::read_to_string(entry.ok()?.path().join("stat")).ok()?;
+            // the command name in parentheses may itself contain spaces
+            let (head, rest) = stat.rsplit_once(')')?;
+            let pid = head.split_whitespace().next()?.parse().ok()?;
+            let mut fields = rest.split_whitespace().skip(1);
+            let ppid = fields.next()?.parse().ok()?;
+            let pgrp = fields.next()?.parse().ok()?;
+            Some((pid, ppid, pgrp))
+        })
+        .collect();
+    while let Some(parent) = parents.pop() {
+        for &(child, _, pgrp) in procs.iter().filter(|(_, ppid, _)| *ppid == parent) {
+            parents.push(child);
+            if !groups.contains(&pgrp) {
+                groups.push(pgrp);
+            }
+        }
+    }
+    for pgrp in groups {
+        unsafe { libc::killpg(pgrp, libc::SIGKILL) };
+    }
+}
+
+/// Collects what `chunks` still delivers of a killed capture's output. The
+/// wait is short, in case a process outside the group holds the pipe open.
+fn drain(chunks: &mpsc::Receiver<(bool, Vec<u8>)>) -> Vec<u8> {
+    let deadline = Instant::now() + Duration::from_millis(100);
+    let mut out = Vec::new();
+    while let Ok((is_stdout, chunk)) =
+        chunks.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    {
+        if !is_stdout {
+            out.extend_from_slice(&chunk);
+        }
+    }
+    out
+}
+
+/// Waits for `child` to exit, giving up at `deadline`. Returns `None` if it
+/// has not exited by then.
+fn wait_until(child: &mut Child, deadline: Option<Instant>) -> io::Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Reads `stream` on a helper thread, sending what it reads as it arrives,
+/// tagged with `is_stdout`. The thread drops its sender at end of file, so
+/// the channel disconnects once every stream sharing it is done.
+fn read_chunks(
+    mut stream: impl Read + Send + 'static,
+    is_stdout: bool,
+    tx: mpsc::Sender<(bool, Vec<u8>)>,
+) {
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    // the receiver is gone once the budget ran out
+                    if tx.send((is_stdout, buf[..n].to_vec())).is_err() {
+                        break;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break,
+            }
+        }
+    });
+}
+
+/// Rejects names that `su` could mistake for options or that no login name
+/// has; see `get_user_env` for the rule.
+pub(crate) fn validate_username(user: &OsStr) -> Result<(), Error> {
+    let name = user.as_bytes();
+    let name = name.strip_suffix(b"$").unwrap_or(name);
+    let valid = !name.is_empty()
+        && name[0] != b'-'
+        && name.iter().all(|&b| {
+            !b.is_ascii() || b.is_ascii_alphanumeric() || matches!(b, b'.' | b'_' | b'-' | b'@')
+        });
+    match valid {
+        true => Ok(()),
+        false => Err(Error::InvalidUsername(user.to_os_string())),
+    }
+}
+
+/// How to read the output of the capture command built by `Capture::new`.
+struct Capture {
+    user: OsString,
+    strip_sudo_vars: bool,
+    restricted: bool,
+    login_shell: Option<PathBuf>,
+}
+
+impl Capture {
+    /// Builds the command that prints `user`'s environment, along with what
+    /// is needed to interpret its output.
+    fn new(
+        user: &OsStr,
+        fallback_shell: &Path,
+        backend: EnvBackend,
+        nologin: NologinPolicy,
+    ) -> Result<(Command, Self), Error> {
+        validate_username(user)?;
+        if get_effective_uid() != 0 {
+            return Err(Error::InsufficientPrivileges);
+        }
+        Capture::build(user, fallback_shell, backend, nologin)
+    }
+
+    /// Like `new`, without checking that the capture could run.
+    fn build(
+        user: &OsStr,
+        fallback_shell: &Path,
+        backend: EnvBackend,
+        nologin: NologinPolicy,
+    ) -> Result<(Command, Self), Error> {
+        // a hanging NSS backend would otherwise stall the capture before its
+        // own timeout even starts
+        let login_shell = match get_user_by_name_with_timeout(user, DEFAULT_LOOKUP_TIMEOUT) {
+            Ok(u) => Some(u.shell().to_path_buf()),
+            Err(CmdError::LookupTimeout(timeout)) => {
+                return Err(Error::Timeout(timeout, String::new()))
+            }
+            Err(_) => None,
+        };
+        Capture::with_login_shell(user, login_shell, fallback_shell, backend, nologin)
+    }
+
+    /// Like `build`, for a user whose passwd entry has `login_shell`.
+    fn with_login_shell(
+        user: &OsStr,
+        login_shell: Option<PathBuf>,
+        fallback_shell: &Path,
+        backend: EnvBackend,
+        nologin: NologinPolicy,
+    ) -> Result<(Command, Self), Error> {
+        let shell = capture_shell(login_shell.as_deref(), fallback_shell, nologin)?;
+
+        let mut cmd = capture_command(backend.program());
+        if backend == EnvBackend::Sudo {
+            if let CaptureShell::Nologin(_) = shell {
+                // sudo has no way to pick another shell
+                return Err(Error::ShellDisallowsLogin(login_shell.unwrap_or_default()));
+            }
+            // sudo -i runs the login shell with -c and the rest of the args
+            // sudo quotes these, so there is no room for a printenv fallback
+            cmd.arg("-n")
+                .arg("-i")
+                .arg("-u")
+                .arg(user)
+                .arg("env")
+                .arg("-0");
+            let capture = Capture {
+                user: user.to_os_string(),
+                strip_sudo_vars: true,
+                restricted: false,
+                login_shell: None,
+            };
+            return Ok((cmd, capture));
+        }
+
+        let restricted = matches!(shell, CaptureShell::Unrestricted(_));
+        match &shell {
+            CaptureShell::Default => {}
+            CaptureShell::Login(shell) => {
+                cmd.arg("-s").arg(shell);
+            }
+            CaptureShell::Unrestricted(shell) => {
+                log::info!(
+                    "{} has a restricted login shell, capturing its environment with {}",
+                    user.to_string_lossy(),
+                    shell.display()
+                );
+                cmd.arg("-s").arg(shell);
+            }
+            CaptureShell::Invalid(shell) => {
+                log::warn!(
+                    "{} has an invalid login shell {}, capturing its environment with {}",
+                    user.to_string_lossy(),
+                    shell.display(),
+                    fallback_shell.display()
+                );
+                cmd.arg("-s").arg(fallback_shell);
+            }
+            CaptureShell::Nologin(shell) => {
+                log::warn!(
+                    "{} has the non-login shell {}, capturing its environment with {}",
+                    user.to_string_lossy(),
+                    shell.display(),
+                    fallback_shell.display()
+                );
+                cmd.arg("-s").arg(fallback_shell);
+            }
+        }
+        // the restricted profile may have pointed PATH somewhere without env
+        let printenv = match restricted {
+            true => "command -p env -0 2>/dev/null || command -p printenv",
+            false => "env -0 2>/dev/null || printenv",
+        };
+        let login = match backend {
+            EnvBackend::Runuser => "-l",
+            _ => "-",
+        };
+        cmd.arg(login).arg(user).arg("-c").arg(printenv);
+        let capture = Capture {
+            user: user.to_os_string(),
+            strip_sudo_vars: false,
+            restricted,
+            login_shell,
+        };
+        Ok((cmd, capture))
+    }
+
+    /// Builds a caller-supplied capture command; see `EnvSource::Custom`.
+    fn custom(argv: &[String], user: &OsStr) -> Result<(Command, Self), Error> {
+        let (program, args) = argv.split_first().ok_or_else(|| {
+            Error::FailedExecutingCommand(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "custom capture command is empty",
+            ))
+        })?;
+        let mut cmd = capture_command(substitute_user(program, user));
+        cmd.args(args.iter().map(|arg| substitute_user(arg, user)));
+        let capture = Capture {
+            user: user.to_os_string(),
+            strip_sudo_vars: false,
+            restricted: false,
+            login_shell: None,
+        };
+        Ok((cmd, capture))
+    }
+
+    /// Parses the capture output into a map.
+    fn parse(&self, output: &[u8]) -> HashMap<OsString, OsString> {
+        let mut env_map = UserEnv::parse_os(output);
+
+        if self.strip_sudo_vars {
+            env_map.retain(|key, _| !key.as_bytes().starts_with(b"SUDO_"));
+        }
+
+        // su sets SHELL to the shell it ran, which is not the user's own one
+        if let (true, Some(shell)) = (self.restricted, &self.login_shell) {
+            env_map.insert("SHELL".into(), shell.clone().into_os_string());
+        }
+        env_map
+    }
+}
+
+/// A command that runs without a terminal or the caller's interactive setup.
+fn capture_command(program: impl AsRef<OsStr>) -> Command {
+    let mut cmd = Command::new(program);
+    for var in INTERACTIVE_VARS {
+        cmd.env_remove(var);
+    }
+    cmd.stdin(Stdio::null());
+    cmd
+}
+
+/// Replaces every `{user}` in `arg` with `user`.
+fn substitute_user(arg: &str, user: &OsStr) -> OsString {
+    let mut out = Vec::with_capacity(arg.len());
+    for (i, part) in arg.split(USER_PLACEHOLDER).enumerate() {
+        if i > 0 {
+            out.extend_from_slice(user.as_bytes());
+        }
+        out.extend_from_slice(part.as_bytes());
+    }
+    OsString::from_vec(out)
+}
+
+/// The shell a capture runs for a given login shell.
+enum CaptureShell {
+    /// No passwd entry, or an empty shell field; left to the backend.
+    Default,
+    Login(PathBuf),
+    /// The unrestricted counterpart of a restricted login shell.
+    Unrestricted(PathBuf),
+    /// The login shell is missing or not executable; the fallback is used.
+    Invalid(PathBuf),
+    /// The login shell refuses logins and `NologinPolicy::Fallback` applies.
+    Nologin(PathBuf),
+}
+
+/// Decides how to capture with `login_shell`, the shell field of the user's
+/// passwd entry.
+fn capture_shell(
+    login_shell: Option<&Path>,
+    fallback: &Path,
+    nologin: NologinPolicy,
+) -> Result<CaptureShell, Error> {
+    let Some(shell) = login_shell.filter(|s| !s.as_os_str().is_empty()) else {
+        return Ok(CaptureShell::Default);
+    };
+    let name = shell
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or_default();
+    if NOLOGIN_SHELLS.contains(&name) {
+        return match nologin {
+            NologinPolicy::Reject => Err(Error::ShellDisallowsLogin(shell.to_path_buf())),
+            NologinPolicy::Fallback => Ok(CaptureShell::Nologin(shell.to_path_buf())),
+        };
+    }
+    if let Some(unrestricted) = unrestricted_shell(shell, fallback, RESTRICTED_SHELLS) {
+        return Ok(CaptureShell::Unrestricted(unrestricted));
+    }
+    match is_valid_shell(shell) {
+        true => Ok(CaptureShell::Login(shell.to_path_buf())),
+        false => Ok(CaptureShell::Invalid(shell.to_path_buf())),
+    }
+}
+
+/// Restricted shells only enforce their restrictions once the startup files
+/// have been read, and may not let `env` run at all (e.g. when PATH is
+/// limited to `~/bin`). Capturing through the unrestricted variant reads the
+/// same startup files, so it yields the same environment without tripping
+/// over the restrictions. Only our fixed capture command runs under it.
+///
+/// `restricted` pairs each restricted shell's name with its unrestricted
+/// variant, as `RESTRICTED_SHELLS` does.
+fn unrestricted_shell(
+    shell: &Path,
+    fallback: &Path,
+    restricted: &[(&str, &str)],
+) -> Option<PathBuf> {
+    let name = shell.file_name()?.to_str()?;
+    let (_, unrestricted) = restricted.iter().find(|(r, _)| *r == name)?;
+    let unrestricted = Path::new(unrestricted);
+    if unrestricted.exists() {
+        Some(unrestricted.to_path_buf())
+    } else {
+        Some(fallback.to_path_buf())
+    }
+}
+
+/// An empty shell field is valid and means `/bin/sh`; anything else must be
+/// an executable file for `su` to start it.
+fn is_valid_shell(shell: &Path) -> bool {
+    shell.as_os_str().is_empty() || is_executable(shell)
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_login_shell_is_captured_with_the_fallback() {
+        let missing = Path::new("/nonexistent/shell");
+        let fallback = Path::new("/bin/sh");
+        assert!(matches!(
+            capture_shell(Some(missing), fallback, NologinPolicy::default()),
+            Ok(CaptureShell::Invalid(shell)) if shell == missing
         ));
+
+        let (cmd, _) = Capture::with_login_shell(
+            OsStr::new("example_user"),
+            Some(missing.to_path_buf()),
+            fallback,
+            EnvBackend::Su,
+            NologinPolicy::default(),
+        )
+        .unwrap();
+        let args: Vec<_> = cmd.get_args().collect();
+        let shell = args
+            .iter()
+            .position(|&arg| arg == "-s")
+            .map(|i| args[i + 1]);
+        assert_eq!(shell, Some(fallback.as_os_str()));
+    }
+
+    #[test]
+    fn capture_command_looks_non_interactive() {
+        let mut cmd = capture_command("sh");
+        let envs: Vec<_> = cmd.get_envs().collect();
+        for var in ["PS1", "PS2", "PROMPT_COMMAND", "BASH_ENV", "ENV"] {
+            assert!(envs.contains(&(OsStr::new(var), None)), "{} is kept", var);
+        }
+
+        // with stdin attached, `read` would wait; with it null `[ -t 0 ]`
+        // fails and `read` hits end of file at once
+        let output = cmd
+            .args([
+                "-c",
+                "[ -t 0 ] && echo tty; read line || echo eof; echo \"$-\"",
+            ])
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let lines: Vec<_> = stdout.lines().collect();
+        assert_eq!(lines[0], "eof");
+        assert!(!lines[1].contains('i'), "interactive flags {:?}", lines[1]);
+    }
+
+    #[test]
+    fn nologin_shells_follow_the_nologin_policy() {
+        let fallback = Path::new("/bin/sh");
+        for nologin in ["/usr/sbin/nologin", "/bin/false"] {
+            let nologin = Path::new(nologin);
+            assert!(matches!(
+                capture_shell(Some(nologin), fallback, NologinPolicy::Reject),
+                Err(Error::ShellDisallowsLogin(shell)) if shell == nologin
+            ));
+            assert!(matches!(
+                capture_shell(Some(nologin), fallback, NologinPolicy::Fallback),
+                Ok(CaptureShell::Nologin(shell)) if shell == nologin
+            ));
+
+            let (cmd, _) = Capture::with_login_shell(
+                OsStr::new("example_user"),
+                Some(nologin.to_path_buf()),
+                fallback,
+                EnvBackend::Su,
+                NologinPolicy::Fallback,
+            )
+            .unwrap();
+            let args: Vec<_> = cmd.get_args().collect();
+            let shell = args
+                .iter()
+                .position(|&arg| arg == "-s")
+                .map(|i| args[i + 1]);
+            assert_eq!(shell, Some(fallback.as_os_str()));
+        }
     }
 
-    // Convert the output bytes to a String
-    let output_str = String::from_utf8_lossy(&output.stdout);
+    #[test]
+    fn restricted_shells_are_captured_unrestricted() {
+        let fallback = Path::new("/bin/sh");
+        let rbash = Path::new("/bin/rbash");
+        let bash = match Path::new("/bin/bash").exists() {
+            true => Path::new("/bin/bash"),
+            false => fallback,
+        };
+        assert!(matches!(
+            capture_shell(Some(rbash), fallback, NologinPolicy::default()),
+            Ok(CaptureShell::Unrestricted(shell)) if shell == bash
+        ));
 
-    // Parse each line of the output
-    let mut env_map = HashMap::new();
-    for line in output_str.lines() {
-        let mut split = line.splitn(2, '=');
-        if let (Some(key), Some(value)) = (split.next(), split.next()) {
-            env_map.insert(key.to_string(), value.to_string());
+        let without_bash = [("rbash", "/nonexistent/bash")];
+        assert_eq!(
+            unrestricted_shell(rbash, fallback, &without_bash),
+            Some(fallback.to_path_buf())
+        );
+        assert_eq!(
+            unrestricted_shell(Path::new("/bin/bash"), fallback, &without_bash),
+            None
+        );
+    }
+
+    #[test]
+    fn only_directory_outages_are_transient() {
+        for stderr in [
+            "su: Authentication service cannot retrieve authentication info",
+            "nss_ldap: failed to bind to LDAP server ldap://ldap: Can't contact LDAP server",
+            "nss_ldap: could not connect to any LDAP server as (null) - Can't contact LDAP server",
+        ] {
+            assert!(
+                Error::CommandExited(stderr.into()).is_transient(),
+                "{}",
+                stderr
+            );
+        }
+        for stderr in [
+            "su: System error",
+            "su: user example_user does not exist or the user entry does not contain all the required fields",
+            "/home/example_user/.profile: line 3: kinit: command not found",
+        ] {
+            assert!(!Error::CommandExited(stderr.into()).is_transient(), "{}", stderr);
         }
+        let unreachable = Error::CommandExited("su: Can't contact LDAP server".into());
+        assert!(Error::BackendFailed(EnvBackend::Runuser, Box::new(unreachable)).is_transient());
+
+        let timeout = Error::Timeout(Duration::from_secs(10), String::new());
+        assert!(!timeout.is_transient());
+        let mut policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::ZERO,
+            retry_timeouts: false,
+        };
+        assert!(!policy.retries(&timeout));
+        policy.retry_timeouts = true;
+        assert!(policy.retries(&timeout));
+    }
+
+    #[test]
+    fn missing_su_falls_back_to_runuser() {
+        let user = OsStr::new("example_user");
+        let capture = |backend| {
+            Capture::build(
+                user,
+                Path::new(DEFAULT_FALLBACK_SHELL),
+                backend,
+                NologinPolicy::default(),
+            )
+        };
+        let tried = Mutex::new(Vec::new());
+        let env = su_then_runuser(user, capture, |cmd, _| {
+            let program = cmd.get_program().to_os_string();
+            tried.lock().unwrap().push(program.clone());
+            match program == EnvBackend::Su.program() {
+                true => Err(Error::FailedExecutingCommand(
+                    io::ErrorKind::NotFound.into(),
+                )),
+                false => Ok(HashMap::from([("CAPTURED_BY".into(), program)])),
+            }
+        })
+        .unwrap();
+        assert_eq!(*tried.lock().unwrap(), ["su", "runuser"]);
+        assert_eq!(env[OsStr::new("CAPTURED_BY")], "runuser");
     }
 
-    Ok(env_map)
+    #[test]
+    fn only_su_messages_make_su_unusable() {
+        let exited = |stderr: &str| Error::CommandExited(stderr.to_string());
+        assert!(su_unusable(&exited(
+            "Password: su: Authentication failure\n"
+        )));
+        assert!(su_unusable(&exited("su: Authentication failure\n")));
+        assert!(!su_unusable(&exited(
+            "Enter the vault password to continue\n"
+        )));
+        assert!(!su_unusable(&exited(
+            "su: user example_user does not exist\n"
+        )));
+    }
 }
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{fchown, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use users::User;
+
+/// The variable through which the child learns where its env file is.
+pub const ENV_FILE_VAR: &str = "POLYJUICE_ENV_FILE";
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A captured environment written out as a shell-sourceable file.
+///
+/// The file holds one `export KEY='VALUE'` line per variable and is owned by,
+/// and only readable by, the target user, so a wrapper script running as
+/// that user can `. "$POLYJUICE_ENV_FILE"` to restore the environment in a
+/// subshell. Variables whose names are not valid shell identifiers (such as
+/// bash's exported `BASH_FUNC_name%%` functions) cannot be exported and are
+/// left out.
+///
+/// The file is removed when the `EnvFile` is dropped, so keep it alive until
+/// the child has exited.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as_user_with_env_file;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let (mut cmd, env_file) = cmd_as_user_with_env_file("/opt/wrapper.sh", user).unwrap();
+/// cmd.status().unwrap();
+/// drop(env_file);
+/// ```
+#[derive(Debug)]
+pub struct EnvFile {
+    path: PathBuf,
+}
+
+impl EnvFile {
+    /// Writes `env` to a new file in the system temp directory, owned by `user`.
+    pub fn write(user: &User, env: &HashMap<String, String>) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "polyjuice-env-{}-{}-{}",
+            user.uid(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        // from here on the file exists and must be cleaned up on failure
+        let env_file = EnvFile { path };
+        fchown(&file, Some(user.uid()), Some(user.primary_group_id()))?;
+
+        let mut keys: Vec<_> = env.keys().filter(|k| is_shell_name(k)).collect();
+        keys.sort();
+        for key in keys {
+            writeln!(file, "export {}={}", key, single_quote(&env[key]))?;
+        }
+        file.sync_all()?;
+        Ok(env_file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Points `ENV_FILE_VAR` in `cmd`'s environment at this file.
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.env(ENV_FILE_VAR, &self.path);
+    }
+}
+
+impl Drop for EnvFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn is_shell_name(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Quotes `value` so the shell reads it back verbatim.
+///
+/// Nothing is special inside single quotes, including newlines, `$` and
+/// backslashes, except the single quote itself; each one is written as
+/// `'\''` (close the quote, an escaped quote, reopen).
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
@@ -0,0 +1,117 @@
+use std::fmt::Display;
+use std::io;
+
+use crate::{CmdError, EnvError, HomeError};
+
+/// Any error polyjuice returns.
+///
+/// Each part of the crate keeps its own error type (`CmdError`, `EnvError`,
+/// `HomeError`, `PamError`), so that callers handling a single function can
+/// match on exactly what it returns. An application that combines several
+/// of them can use this instead: every one of them converts into it, so `?`
+/// works throughout without `map_err`.
+///
+/// Every function that builds or runs a command (the `cmd_as_user` family,
+/// `CommandBuilder`, `run_as_user` and the like) returns `CmdError`, with a
+/// failed capture inside `CmdError::FailedGettingEnv`; only the functions
+/// that capture an environment without building a command, the
+/// `get_user_env` family, return `EnvError`.
+///
+/// The conversions flatten where they can: `CmdError::FailedGettingEnv`
+/// becomes `Error::EnvFetch`, the `io::Error` of `CmdError::SpawnFailed` and
+/// `CmdError::FailedWritingEnvFile` becomes `Error::Io`, and
+/// `HomeError::PamSessionFailed` becomes `Error::Pam`. Anything else is kept
+/// whole in `Error::Command` or `Error::Home`; in particular
+/// `CmdError::MissingBuilderField` is not `Error::UserNotFound`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_username, get_user_env};
+///
+/// fn launch(username: &str) -> Result<(), polyjuice::Error> {
+///     let env = get_user_env(username)?;
+///     println!("running R from {}", env.get("PATH").unwrap_or_default());
+///     let status = cmd_as_username("R", username.to_string())?.status()?;
+///     println!("R exited with {}", status);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub enum Error {
+    UserNotFound,
+    EnvFetch(EnvError),
+    Io(io::Error),
+    Command(CmdError),
+    Home(HomeError),
+    #[cfg(feature = "pam")]
+    Pam(crate::PamError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UserNotFound => write!(f, "User not found"),
+            Error::EnvFetch(e) => write!(f, "Failed to get user environment: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Command(e) => write!(f, "{}", e),
+            Error::Home(e) => write!(f, "{}", e),
+            #[cfg(feature = "pam")]
+            Error::Pam(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::UserNotFound => None,
+            Error::EnvFetch(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Command(e) => Some(e),
+            Error::Home(e) => Some(e),
+            #[cfg(feature = "pam")]
+            Error::Pam(e) => Some(e),
+        }
+    }
+}
+
+impl From<CmdError> for Error {
+    fn from(e: CmdError) -> Self {
+        match e {
+            CmdError::UserNotFound => Error::UserNotFound,
+            CmdError::FailedGettingEnv(e) => Error::EnvFetch(e),
+            CmdError::FailedWritingEnvFile(e) | CmdError::SpawnFailed(e) => Error::Io(e),
+            e => Error::Command(e),
+        }
+    }
+}
+
+impl From<EnvError> for Error {
+    fn from(e: EnvError) -> Self {
+        Error::EnvFetch(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<HomeError> for Error {
+    fn from(e: HomeError) -> Self {
+        match e {
+            #[cfg(feature = "pam")]
+            HomeError::PamSessionFailed(e) => Error::Pam(e),
+            e => Error::Home(e),
+        }
+    }
+}
+
+#[cfg(feature = "pam")]
+impl From<crate::PamError> for Error {
+    fn from(e: crate::PamError) -> Self {
+        Error::Pam(e)
+    }
+}
@@ -0,0 +1,350 @@
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{chown, DirBuilderExt};
+use std::path::{Path, PathBuf};
+
+use users::os::unix::UserExt;
+use users::User;
+
+const SKEL_DIR: &str = "/etc/skel";
+
+/// The home directory `NoHomePolicy::UseDefault` falls back to.
+pub const DEFAULT_HOME: &str = "/";
+
+#[derive(Debug)]
+pub enum HomeError {
+    CreateFailed(PathBuf, io::Error),
+    #[cfg(feature = "pam")]
+    PamSessionFailed(crate::PamError),
+    StillMissing(PathBuf),
+    NoHomeConfigured(String),
+}
+
+impl Display for HomeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HomeError::CreateFailed(path, e) => {
+                write!(f, "Failed to create {}: {}", path.display(), e)
+            }
+            #[cfg(feature = "pam")]
+            HomeError::PamSessionFailed(e) => write!(f, "Failed to open PAM session: {}", e),
+            HomeError::StillMissing(path) => {
+                write!(f, "Home directory {} still does not exist", path.display())
+            }
+            HomeError::NoHomeConfigured(name) => {
+                write!(f, "User {} has no home directory configured", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for HomeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HomeError::CreateFailed(_, e) => Some(e),
+            #[cfg(feature = "pam")]
+            HomeError::PamSessionFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// How a missing home directory gets created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HomeCreation {
+    /// Open a PAM session and let the PAM stack (typically `pam_mkhomedir`)
+    /// create it, so site policy for permissions and skeleton files applies.
+    #[cfg(feature = "pam")]
+    Pam,
+    /// Create the directory with mode 0700, copy `/etc/skel` into it and hand
+    /// everything to the user and their primary group.
+    Manual,
+}
+
+impl Default for HomeCreation {
+    /// `Pam` when the `pam` feature is enabled, `Manual` otherwise.
+    fn default() -> Self {
+        #[cfg(feature = "pam")]
+        {
+            HomeCreation::Pam
+        }
+        #[cfg(not(feature = "pam"))]
+        {
+            HomeCreation::Manual
+        }
+    }
+}
+
+/// What `ensure_home_dir` found or did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HomeStatus {
+    /// The directory was already there; nothing was done.
+    AlreadyExisted,
+    /// A PAM session was opened and the directory exists now.
+    #[cfg(feature = "pam")]
+    CreatedViaPam,
+    /// The directory was created and filled from `/etc/skel`.
+    CreatedManually,
+    /// Creation ran without error, but the directory still does not exist,
+    /// e.g. because the PAM stack has no `pam_mkhomedir`.
+    StillMissing,
+}
+
+/// What to do for an account whose passwd entry has an empty home field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NoHomePolicy {
+    /// Fail with `HomeError::NoHomeConfigured`.
+    #[default]
+    Error,
+    /// Use `DEFAULT_HOME` (`/`) as the home directory.
+    UseDefault,
+    /// Carry on without a home directory, skipping every step that needs one.
+    Skip,
+}
+
+/// Returns the user's home directory, applying `policy` if the passwd entry
+/// leaves it empty.
+///
+/// `Ok(None)` means the policy is `NoHomePolicy::Skip` and there is no home
+/// directory to work with.
+///
+/// # Errors
+///
+/// Returns `HomeError::NoHomeConfigured` if the home field is empty and the
+/// policy is `NoHomePolicy::Error`.
+///
+/// # Examples
+///
+/// ```
+/// use polyjuice::{configured_home, HomeError, NoHomePolicy};
+///
+/// use users::os::unix::UserExt;
+///
+/// // as if passwd held `nohome:x:4242:4242::` with nothing in the home field
+/// let user = users::User::new(4242, "nohome", 4242).with_home_dir("");
+/// assert!(matches!(
+///     configured_home(&user, NoHomePolicy::Error),
+///     Err(HomeError::NoHomeConfigured(_))
+/// ));
+/// assert_eq!(
+///     configured_home(&user, NoHomePolicy::UseDefault).unwrap().unwrap(),
+///     std::path::Path::new("/")
+/// );
+/// assert!(configured_home(&user, NoHomePolicy::Skip).unwrap().is_none());
+/// ```
+pub fn configured_home(user: &User, policy: NoHomePolicy) -> Result<Option<PathBuf>, HomeError> {
+    let home = user.home_dir();
+    if !home.as_os_str().is_empty() {
+        return Ok(Some(home.to_path_buf()));
+    }
+    match policy {
+        NoHomePolicy::Error => Err(HomeError::NoHomeConfigured(
+            user.name().to_string_lossy().to_string(),
+        )),
+        NoHomePolicy::UseDefault => Ok(Some(PathBuf::from(DEFAULT_HOME))),
+        NoHomePolicy::Skip => Ok(None),
+    }
+}
+
+/// Makes sure the user's home directory exists, creating it with
+/// `HomeCreation::default()` if it is missing.
+///
+/// This is the check-create-check sequence of `ensure_home_then` on its
+/// own, for services that provision home directories without launching
+/// anything, and it reports which way it went. A directory that is still
+/// missing after creation is a `HomeStatus::StillMissing` rather than an
+/// error, so the caller decides what that means.
+///
+/// # Errors
+///
+/// Returns `HomeError::NoHomeConfigured` for an account with an empty home
+/// field, and the error of the creation mechanism if it fails outright.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{ensure_home_dir, HomeStatus};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// match ensure_home_dir(&user).unwrap() {
+///     HomeStatus::AlreadyExisted => {}
+///     HomeStatus::StillMissing => eprintln!("home directory could not be provisioned"),
+///     created => println!("home directory provisioned: {:?}", created),
+/// }
+/// ```
+pub fn ensure_home_dir(user: &User) -> Result<HomeStatus, HomeError> {
+    ensure_home_dir_with(user, HomeCreation::default())
+}
+
+/// Like `ensure_home_dir`, creating a missing home directory with `creation`.
+///
+/// # Examples
+///
+/// ```
+/// use polyjuice::{ensure_home_dir_with, HomeCreation, HomeStatus};
+/// use users::os::unix::UserExt;
+///
+/// let home = std::env::temp_dir().join(format!("polyjuice-home-{}", std::process::id()));
+/// let user = users::User::new(users::get_current_uid(), "example_user", users::get_current_gid())
+///     .with_home_dir(&home);
+///
+/// let status = ensure_home_dir_with(&user, HomeCreation::Manual).unwrap();
+/// assert_eq!(status, HomeStatus::CreatedManually);
+/// let status = ensure_home_dir_with(&user, HomeCreation::Manual).unwrap();
+/// assert_eq!(status, HomeStatus::AlreadyExisted);
+/// # std::fs::remove_dir_all(&home).unwrap();
+/// ```
+pub fn ensure_home_dir_with(user: &User, creation: HomeCreation) -> Result<HomeStatus, HomeError> {
+    let home = configured_home(user, NoHomePolicy::Error)?.expect("only Skip gives no home");
+    provision_home(user, &home, creation)
+}
+
+/// Makes sure the user's home directory exists, then runs `action`.
+///
+/// This is the provisioning-then-run sequence needed before launching
+/// anything as a user who may never have logged in: check for the home
+/// directory, create it if it is missing, check again, and only then
+/// proceed. The directory is created with `HomeCreation::default()`; use
+/// `ensure_home_then_with` to choose the mechanism.
+///
+/// # Errors
+///
+/// Returns a `HomeError` without running `action` if the directory could not
+/// be created, including the case where creation appeared to succeed but the
+/// directory is still missing (e.g. a PAM stack without `pam_mkhomedir`).
+/// An account with an empty home field is rejected with
+/// `HomeError::NoHomeConfigured`; see `ensure_home_then_with_policy`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user, ensure_home_then};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let status = ensure_home_then(&user, || {
+///     cmd_as_user("ls", user.clone()).unwrap().status()
+/// });
+/// ```
+pub fn ensure_home_then<T>(user: &User, action: impl FnOnce() -> T) -> Result<T, HomeError> {
+    ensure_home_then_with(user, HomeCreation::default(), action)
+}
+
+/// Like `ensure_home_then`, creating a missing home directory with `creation`.
+pub fn ensure_home_then_with<T>(
+    user: &User,
+    creation: HomeCreation,
+    action: impl FnOnce() -> T,
+) -> Result<T, HomeError> {
+    ensure_home_then_with_policy(user, creation, NoHomePolicy::default(), action)
+}
+
+/// Like `ensure_home_then_with`, handling an empty home field per `no_home`.
+///
+/// With `NoHomePolicy::Skip` there is nothing to create and `action` runs
+/// straight away; with `NoHomePolicy::UseDefault`, `/` is the directory that
+/// must exist.
+///
+/// # Examples
+///
+/// ```
+/// use polyjuice::{ensure_home_then_with_policy, HomeCreation, HomeError, NoHomePolicy};
+/// use users::os::unix::UserExt;
+///
+/// let user = users::User::new(4242, "nohome", 4242).with_home_dir("");
+/// let ran = ensure_home_then_with_policy(&user, HomeCreation::Manual, NoHomePolicy::Skip, || true);
+/// assert!(ran.unwrap());
+///
+/// let ran = ensure_home_then_with_policy(&user, HomeCreation::Manual, NoHomePolicy::Error, || true);
+/// assert!(matches!(ran, Err(HomeError::NoHomeConfigured(_))));
+/// ```
+pub fn ensure_home_then_with_policy<T>(
+    user: &User,
+    creation: HomeCreation,
+    no_home: NoHomePolicy,
+    action: impl FnOnce() -> T,
+) -> Result<T, HomeError> {
+    let Some(home) = configured_home(user, no_home)? else {
+        return Ok(action());
+    };
+    if provision_home(user, &home, creation)? == HomeStatus::StillMissing {
+        return Err(HomeError::StillMissing(home));
+    }
+    Ok(action())
+}
+
+fn provision_home(
+    user: &User,
+    home: &Path,
+    creation: HomeCreation,
+) -> Result<HomeStatus, HomeError> {
+    if home.is_dir() {
+        return Ok(HomeStatus::AlreadyExisted);
+    }
+    let created = match creation {
+        #[cfg(feature = "pam")]
+        HomeCreation::Pam => {
+            crate::try_pam_session(user.name().to_string_lossy().to_string())
+                .map_err(HomeError::PamSessionFailed)?;
+            HomeStatus::CreatedViaPam
+        }
+        HomeCreation::Manual => {
+            create_home(user, home)?;
+            HomeStatus::CreatedManually
+        }
+    };
+    match home.is_dir() {
+        true => Ok(created),
+        false => Ok(HomeStatus::StillMissing),
+    }
+}
+
+fn create_home(user: &User, home: &Path) -> Result<(), HomeError> {
+    let failed = |path: &Path| {
+        let path = path.to_path_buf();
+        move |e| HomeError::CreateFailed(path, e)
+    };
+    if let Some(parent) = home.parent() {
+        fs::create_dir_all(parent).map_err(failed(parent))?;
+    }
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(home)
+        .map_err(failed(home))?;
+    let owner = (Some(user.uid()), Some(user.primary_group_id()));
+    chown(home, owner.0, owner.1).map_err(failed(home))?;
+    copy_skel(Path::new(SKEL_DIR), home, owner)
+}
+
+/// Copies the skeleton tree into `dest`, preserving modes and symlinks and
+/// giving every copied entry to `owner`.
+fn copy_skel(src: &Path, dest: &Path, owner: (Option<u32>, Option<u32>)) -> Result<(), HomeError> {
+    let entries = match fs::read_dir(src) {
+        Ok(entries) => entries,
+        // no skeleton to copy
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(HomeError::CreateFailed(dest.to_path_buf(), e)),
+    };
+    for entry in entries {
+        let entry = entry.map_err(|e| HomeError::CreateFailed(dest.to_path_buf(), e))?;
+        let target = dest.join(entry.file_name());
+        let failed = |e| HomeError::CreateFailed(target.clone(), e);
+        let file_type = entry.file_type().map_err(failed)?;
+        if file_type.is_symlink() {
+            let link = fs::read_link(entry.path()).map_err(failed)?;
+            std::os::unix::fs::symlink(link, &target).map_err(failed)?;
+            std::os::unix::fs::lchown(&target, owner.0, owner.1).map_err(failed)?;
+            continue;
+        }
+        if file_type.is_dir() {
+            fs::create_dir(&target).map_err(failed)?;
+            copy_skel(&entry.path(), &target, owner)?;
+        } else {
+            fs::copy(entry.path(), &target).map_err(failed)?;
+        }
+        let permissions = entry.metadata().map_err(failed)?.permissions();
+        fs::set_permissions(&target, permissions).map_err(failed)?;
+        chown(&target, owner.0, owner.1).map_err(failed)?;
+    }
+    Ok(())
+}
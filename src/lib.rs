@@ -1,24 +1,183 @@
-use std::{ffi::OsStr, fmt::Display, os::unix::process::CommandExt, process::Command};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+    time::Duration,
+};
 
-use env::get_user_env;
+use pre_exec::PrivilegeDrop;
 
-#[cfg(feature = "pam")]
-use pam_client::{Context, Flag};
+use users::os::unix::UserExt;
 use users::User;
 
+mod builder;
+mod child;
+mod drift;
 mod env;
+mod env_file;
+mod error;
+mod home;
+mod limit;
+mod locale;
+mod lookup;
+mod minimal;
+mod output;
+#[cfg(feature = "pam")]
+mod pam;
+mod passthrough;
+mod policy;
+mod pre_exec;
+#[cfg(feature = "pty")]
+mod pty;
+#[cfg(target_os = "linux")]
+mod reap;
+mod rlimit;
+mod sanitize;
+mod scoped_home;
+mod shell;
+mod snapshot;
+mod stdio;
+#[cfg(feature = "tokio")]
+mod stream;
+mod supervise;
+#[cfg(all(feature = "utmp", target_os = "linux"))]
+mod utmp;
+mod verify;
+
+pub use builder::CommandBuilder;
+pub use child::{spawn_as_user, UserChild};
+pub use drift::{assert_env_matches, EnvDiff, EnvDrift};
+pub use env::{
+    get_user_env, get_user_env_os, get_user_env_with_backend, get_user_env_with_budget,
+    get_user_env_with_fallback_shell, get_user_env_with_nologin_policy, get_user_env_with_retries,
+    get_user_env_with_retry_policy, get_user_env_with_source, get_user_env_with_timeout,
+    BudgetPolicy, CacheOutcome, CaptureBudget, EnvBackend, EnvPolicy, EnvSource, Error as EnvError,
+    NologinPolicy, RetryPolicy, UserEnv, UserEnvCache, DEFAULT_CAPTURE_TIMEOUT,
+    DEFAULT_FALLBACK_SHELL, USER_PLACEHOLDER,
+};
+#[cfg(feature = "tokio")]
+pub use env::{get_user_env_async, get_user_env_async_with_budget};
+pub use env_file::{EnvFile, ENV_FILE_VAR};
+pub use error::Error;
+pub use home::{
+    configured_home, ensure_home_dir, ensure_home_dir_with, ensure_home_then,
+    ensure_home_then_with, ensure_home_then_with_policy, HomeCreation, HomeError, HomeStatus,
+    NoHomePolicy, DEFAULT_HOME,
+};
+pub use limit::{ConcurrencyLimiter, LaunchSlot, LimitMode};
+pub use locale::{load_locale_file, SYSTEM_LOCALE_FILES};
+pub use lookup::{
+    get_user_by_name_with_timeout, get_user_by_uid_with_timeout, UserIdent, DEFAULT_LOOKUP_TIMEOUT,
+};
+pub use minimal::{get_minimal_user_env, DEFAULT_ROOT_PATH, DEFAULT_USER_PATH};
+pub use output::{
+    run_as_user, run_as_user_checked, run_as_user_streaming, run_as_username, run_prefixed,
+    StreamKind,
+};
+#[cfg(feature = "pam")]
+pub use pam::{
+    authenticate_pam_session, open_pam_session, run_in_pam_session, try_pam_session,
+    try_pam_session_env, try_pam_session_with_retry, try_pam_session_with_service,
+    try_pam_session_with_timeout, PamEnvPrecedence, PamError, PamSessionGuard, DEFAULT_PAM_SERVICE,
+};
+pub use passthrough::PassthroughVars;
+pub use policy::{ProgramPolicy, Subject};
+pub use pre_exec::{detach, GroupPolicy};
+#[cfg(feature = "pty")]
+pub use pty::{spawn_in_pty, PtyChild, PtyReader};
+#[cfg(target_os = "linux")]
+pub use reap::{reap_children, spawn_reaper_thread};
+pub use rlimit::{Resource, Rlimits};
+pub use sanitize::{EnvSanitizer, DEFAULT_DENIED_VARS};
+pub use scoped_home::{scoped_home, ScopedHome};
+pub use shell::{shell_as_user, shell_as_user_with_nologin_policy};
+pub use snapshot::{load_env_snapshot, save_env_snapshot};
+pub use stdio::StdioFds;
+#[cfg(feature = "tokio")]
+pub use stream::{stream_output_as_user, OutputLine, OutputStream};
+pub use supervise::run_as_user_supervised;
+#[cfg(all(feature = "utmp", target_os = "linux"))]
+pub use utmp::UtmpSession;
+pub use verify::{verify_launch_plan, Credentials};
 
 #[derive(Debug)]
 pub enum CmdError {
     UserNotFound,
+    /// No user has this uid.
+    UidNotFound(u32),
+    LookupTimeout(Duration),
     FailedGettingEnv(env::Error),
+    FailedWritingEnvFile(io::Error),
+    ProgramNotFound(OsString),
+    ProgramNotPermitted(PathBuf),
+    SpawnFailed(io::Error),
+    ConcurrencyLimitReached(u32, usize),
+    /// A umask with bits outside `0o777`.
+    InvalidUmask(u32),
+    /// A nice value outside -20..=19.
+    InvalidNice(i32),
+    /// A command that ran but exited unsuccessfully, with its captured
+    /// stderr.
+    CommandFailed(ExitStatus, Vec<u8>),
+    /// `CommandBuilder::build` was called without setting the named field.
+    MissingBuilderField(&'static str),
+    /// The command for this uid needs other groups than the caller's, and
+    /// only root can set them.
+    GroupsNotSettable(u32),
 }
 
 impl Display for CmdError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             CmdError::UserNotFound => write!(f, "User not found"),
+            CmdError::UidNotFound(uid) => write!(f, "No user with uid {}", uid),
+            CmdError::LookupTimeout(t) => write!(f, "User lookup timed out after {:?}", t),
             CmdError::FailedGettingEnv(e) => write!(f, "Failed to get user environment: {}", e),
+            CmdError::FailedWritingEnvFile(e) => write!(f, "Failed to write env file: {}", e),
+            CmdError::ProgramNotFound(p) => write!(f, "Program not found: {}", p.to_string_lossy()),
+            CmdError::ProgramNotPermitted(p) => {
+                write!(f, "Program not permitted: {}", p.display())
+            }
+            CmdError::SpawnFailed(e) => write!(f, "Failed to spawn command: {}", e),
+            CmdError::ConcurrencyLimitReached(uid, limit) => write!(
+                f,
+                "User {} already has {} concurrent launches running",
+                uid, limit
+            ),
+            CmdError::InvalidUmask(mode) => {
+                write!(f, "Invalid umask {:#o}, must be at most 0o777", mode)
+            }
+            CmdError::InvalidNice(nice) => {
+                write!(f, "Invalid nice value {}, must be from -20 to 19", nice)
+            }
+            CmdError::CommandFailed(status, stderr) => {
+                write!(f, "Command failed with {}", status)?;
+                match String::from_utf8_lossy(stderr).trim() {
+                    "" => Ok(()),
+                    stderr => write!(f, ": {}", stderr),
+                }
+            }
+            CmdError::MissingBuilderField(field) => {
+                write!(f, "Command builder has no {} set", field)
+            }
+            CmdError::GroupsNotSettable(uid) => write!(
+                f,
+                "Cannot give the command for uid {} its groups without root",
+                uid
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CmdError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CmdError::FailedGettingEnv(e) => Some(e),
+            CmdError::FailedWritingEnvFile(e) | CmdError::SpawnFailed(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -35,21 +194,34 @@ impl Display for CmdError {
 /// # Returns
 ///
 /// If successful, returns a `Result` containing the new `Command` instance.
-/// On failure, returns a `Result` containing a `SetRunUserError` variant.
+/// On failure, returns a `Result` containing a `CmdError` variant.
 ///
 /// # Errors
 ///
-/// Returns a `SetRunUserError::UserNotFound` error if the user is not found.
-/// Returns a `SetRunUserError::FailedGettingEnv` error if there is an issue getting the user's environment variables.
+/// Returns a `CmdError::UserNotFound` error if the user is not found.
+/// Returns a `CmdError::LookupTimeout` error if the user lookup does not answer within
+/// `DEFAULT_LOOKUP_TIMEOUT`.
+/// Returns a `CmdError::FailedGettingEnv` error if there is an issue getting the user's environment variables.
+/// A username `get_user_env` would reject is reported as `EnvError::InvalidUsername`
+/// inside `CmdError::FailedGettingEnv`, before it is looked up.
 ///
 /// # Examples
 ///
+/// ```
+/// use polyjuice::{cmd_as_username, CmdError, EnvError};
+///
+/// assert!(matches!(
+///     cmd_as_username("ls", "-l"),
+///     Err(CmdError::FailedGettingEnv(EnvError::InvalidUsername(_)))
+/// ));
+/// ```
+///
 /// ```no_run
-/// use your_crate_name::cmd_as_user;
+/// use polyjuice::cmd_as_username;
 ///
 /// let program = "ls";
 /// let username = "example_user".to_string();
-/// match cmd_as_user(program, username) {
+/// match cmd_as_username(program, username) {
 ///     Ok(cmd) => {
 ///         // Use the new command instance
 ///     }
@@ -62,8 +234,93 @@ pub fn cmd_as_username(
     program: impl AsRef<OsStr>,
     username: impl AsRef<OsStr>,
 ) -> Result<Command, CmdError> {
-    let user = users::get_user_by_name(&username).ok_or(CmdError::UserNotFound)?;
-    cmd_as_user(&program, user).map_err(|e| CmdError::FailedGettingEnv(e))
+    cmd_as_username_with_timeout(program, username, DEFAULT_LOOKUP_TIMEOUT)
+}
+
+/// Like `cmd_as_username`, but with an explicit bound on how long the user lookup may take.
+///
+/// See `get_user_by_name_with_timeout` for how the timeout is enforced.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{cmd_as_username_with_timeout, CmdError};
+///
+/// match cmd_as_username_with_timeout("ls", "example_user", Duration::from_secs(2)) {
+///     Ok(cmd) => {
+///         // Use the new command instance
+///     }
+///     Err(CmdError::LookupTimeout(_)) => eprintln!("directory service is not answering"),
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+pub fn cmd_as_username_with_timeout(
+    program: impl AsRef<OsStr>,
+    username: impl AsRef<OsStr>,
+    lookup_timeout: Duration,
+) -> Result<Command, CmdError> {
+    env::validate_username(username.as_ref()).map_err(CmdError::FailedGettingEnv)?;
+    let user = get_user_by_name_with_timeout(&username, lookup_timeout)?;
+    cmd_as_user(&program, user)
+}
+
+/// Creates a command running `program` as the user with the given `uid`.
+///
+/// This is the entry point for callers that already have a numeric uid, e.g.
+/// read from an audit log or `/proc`. The lookup is bounded by
+/// `DEFAULT_LOOKUP_TIMEOUT`.
+///
+/// # Errors
+///
+/// Returns `CmdError::UidNotFound` if no user has that uid,
+/// `CmdError::LookupTimeout` if the lookup does not answer in time, and
+/// `CmdError::FailedGettingEnv` if the user's environment cannot be captured.
+///
+/// # Examples
+///
+/// ```
+/// use polyjuice::{cmd_as_uid, CmdError};
+///
+/// // no account has this uid
+/// assert!(matches!(
+///     cmd_as_uid("ls", 4_000_000_000),
+///     Err(CmdError::UidNotFound(4_000_000_000))
+/// ));
+/// ```
+pub fn cmd_as_uid(program: impl AsRef<OsStr>, uid: u32) -> Result<Command, CmdError> {
+    let user = get_user_by_uid_with_timeout(uid, DEFAULT_LOOKUP_TIMEOUT)?;
+    cmd_as_user(program, user)
+}
+
+/// Creates a command running `program` as the user named or numbered by `ident`.
+///
+/// This accepts whatever identifier the caller has: a `&str`/`String` name,
+/// a `u32` uid, or an explicit `UserIdent`. A string that is all digits is
+/// treated as a uid unless a user with that literal name exists; see
+/// `UserIdent` for the exact rule.
+///
+/// # Errors
+///
+/// Returns `CmdError::UserNotFound` (`CmdError::UidNotFound` for a `u32`) or
+/// `CmdError::LookupTimeout` if the user cannot be resolved, and
+/// `CmdError::FailedGettingEnv` if their environment cannot be captured.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as;
+///
+/// let by_name = cmd_as("ls", "example_user");
+/// let by_uid = cmd_as("ls", 1000);
+/// let from_cli = cmd_as("ls", std::env::args().nth(1).unwrap());
+/// ```
+pub fn cmd_as(
+    program: impl AsRef<OsStr>,
+    ident: impl Into<UserIdent>,
+) -> Result<Command, CmdError> {
+    let user = ident.into().resolve()?;
+    cmd_as_user(program, user)
 }
 
 /// Creates a new command instance configured to run as a specific user.
@@ -81,29 +338,32 @@ pub fn cmd_as_username(
 ///
 /// Returns a `Result` containing:
 /// - `Ok(Command)`: A configured `Command` instance if successful.
-/// - `Err(env::Error)`: An error if retrieving the user's environment variables fails.
+/// - `Err(CmdError)`: An error if retrieving the user's environment variables fails.
 ///
 /// # Details
 ///
 /// The function performs the following steps:
-/// 1. Retrieves the user's environment variables.
+/// 1. Retrieves the user's environment variables and removes the ones
+///    `EnvSanitizer::default()` denies, such as `LD_PRELOAD`.
 /// 2. Creates a new `Command` instance for the specified program.
-/// 3. Sets the UID and GID of the command to match the specified user.
+/// 3. Sets the UID, GID and supplementary groups of the command to match the
+///    specified user.
 /// 4. Clears any existing environment variables and sets them to the user's environment.
+/// 5. Sets the working directory to the user's home directory, as a login shell
+///    would. If the home directory does not exist the working directory is left
+///    as it is and a warning is logged; use `cmd_as_user_in_dir` to choose it.
 ///
 /// # Errors
 ///
-/// This function will return an `Err` if:
-/// - The call to `get_user_env` fails, which could happen if the user's environment
-///   cannot be retrieved or parsed correctly.
+/// Returns `CmdError::FailedGettingEnv` if the user's environment cannot be
+/// retrieved or parsed correctly.
 ///
 /// # Examples
 ///
 /// ```no_run
-/// use users::User;
-/// use your_crate_name::cmd_as_user;
+/// use polyjuice::cmd_as_user;
 ///
-/// let user = User::from_uid(1000).expect("Failed to get user");
+/// let user = users::get_user_by_uid(1000).expect("Failed to get user");
 /// match cmd_as_user("ls", user) {
 ///     Ok(mut cmd) => {
 ///         // The command is now configured to run as the specified user
@@ -115,6 +375,23 @@ pub fn cmd_as_username(
 /// }
 /// ```
 ///
+/// # Supplementary Groups
+///
+/// When the effective UID is root, the child gets the user's full group set:
+/// the primary group plus every group listing the user as a member, as a
+/// login would give it. Otherwise `setgroups` is not permitted, and the child
+/// keeps the caller's supplementary groups.
+///
+/// ```no_run
+/// use std::process::Command;
+/// use polyjuice::cmd_as_user;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let launched = cmd_as_user("id", user).unwrap().arg("-G").output().unwrap();
+/// let login = Command::new("id").args(["-G", "example_user"]).output().unwrap();
+/// assert_eq!(launched.stdout, login.stdout);
+/// ```
+///
 /// # Security Considerations
 ///
 /// This function allows running commands as different users, which can have significant
@@ -122,63 +399,584 @@ pub fn cmd_as_username(
 /// - The calling process has the necessary privileges to switch users.
 /// - The `program` parameter is properly sanitized to prevent command injection.
 /// - The `User` object is obtained from a trusted source.
-pub fn cmd_as_user(program: impl AsRef<OsStr>, user: User) -> Result<Command, env::Error> {
-    let env = get_user_env(user.name().to_string_lossy().to_string())?;
+pub fn cmd_as_user(program: impl AsRef<OsStr>, user: User) -> Result<Command, CmdError> {
+    cmd_as_user_with_sanitizer(program, user, &EnvSanitizer::default())
+}
 
-    let mut new_cmd = Command::new(program);
-    new_cmd.uid(user.uid()).gid(user.primary_group_id());
-    new_cmd.env_clear().envs(env);
+/// Like `cmd_as_user`, but cleans the captured environment with `sanitizer`
+/// instead of the default one.
+///
+/// # Errors
+///
+/// As for `cmd_as_user`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_sanitizer, EnvSanitizer};
+///
+/// let mut sanitizer = EnvSanitizer::default();
+/// sanitizer.allow("LD_LIBRARY_PATH");
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_sanitizer("R", user, &sanitizer);
+/// ```
+pub fn cmd_as_user_with_sanitizer(
+    program: impl AsRef<OsStr>,
+    user: User,
+    sanitizer: &EnvSanitizer,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .sanitizer(sanitizer.clone())
+        .command(program, &user)
+}
 
-    Ok(new_cmd)
+/// Like `cmd_as_user`, but gives the command the groups `groups` asks for.
+///
+/// `cmd_as_user` uses `GroupPolicy::Supplementary`.
+///
+/// # Errors
+///
+/// As for `cmd_as_user`, and `CmdError::GroupsNotSettable` if the caller is
+/// not root and does not already have the groups `policy` asks for.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_groups, GroupPolicy};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let output = cmd_as_user_with_groups("id", user, GroupPolicy::PrimaryOnly)
+///     .unwrap()
+///     .arg("-G")
+///     .output()
+///     .unwrap();
+/// ```
+pub fn cmd_as_user_with_groups(
+    program: impl AsRef<OsStr>,
+    user: User,
+    groups: GroupPolicy,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .supplementary_groups(groups == GroupPolicy::Supplementary)
+        .command(program, &user)
 }
 
-/// Attempts to create a PAM session for a specified user.
+/// Like `cmd_as_user`, but captures the environment asynchronously and
+/// returns a `tokio::process::Command`.
 ///
-/// This function initializes a PAM context for the given username and tries to
-/// open a session. It's intended for authentication and session management
-/// using PAM (Pluggable Authentication Modules).
+/// The blocking capture in `cmd_as_user` ties up a thread for as long as the
+/// user's login profile takes, which serializes launches on a small
+/// runtime. This builds the command with `CommandBuilder::build_async`
+/// instead, so many commands can be prepared concurrently. Must be called
+/// within a tokio runtime with IO and time enabled, and the returned command
+/// spawned within one.
 ///
-/// This is particularly useful to prompt PAM to activated session related triggers
-/// such as pam_mkhomedir
+/// # Errors
 ///
-/// # Parameters
+/// As for `cmd_as_user`.
 ///
-/// * `username`: The username for which to create the PAM session. This should
-///   be a valid username on the system.
+/// # Examples
 ///
-/// # Returns
+/// ```no_run
+/// # async fn example() {
+/// use polyjuice::cmd_as_user_async;
 ///
-/// If successful, returns `Ok(())`. On failure, returns a `Box<dyn std::error::Error>`
-/// with the error details.
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let status = cmd_as_user_async("R", user).await.unwrap().status().await;
+/// # }
+/// ```
+#[cfg(feature = "tokio")]
+pub async fn cmd_as_user_async(
+    program: impl AsRef<OsStr>,
+    user: User,
+) -> Result<tokio::process::Command, CmdError> {
+    CommandBuilder::new()
+        .program(program)
+        .user(user)
+        .build_async()
+        .await
+}
+
+/// Like `cmd_as_user`, but combines the user's environment with the
+/// caller's according to `policy`.
+///
+/// `cmd_as_user` behaves like `EnvPolicy::Replace`. The merge policies keep
+/// variables the caller set for the job; see `EnvPolicy` for which side wins.
+/// With `EnvPolicy::InheritCaller` the user's environment is not captured at
+/// all and this cannot fail.
 ///
 /// # Errors
 ///
-/// Returns an error if:
+/// As for `cmd_as_user`.
 ///
-/// - The PAM context cannot be initialized (e.g., if the provided username is invalid).
-/// - The account management step (`acct_mgmt`) fails.
-/// - The session cannot be opened.
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_policy, EnvPolicy};
+///
+/// std::env::set_var("OMP_NUM_THREADS", "4");
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_policy("Rscript", user, EnvPolicy::MergePreferCaller).unwrap();
+/// assert!(cmd
+///     .get_envs()
+///     .any(|(key, value)| key == "OMP_NUM_THREADS" && value == Some("4".as_ref())));
+/// ```
+pub fn cmd_as_user_with_policy(
+    program: impl AsRef<OsStr>,
+    user: User,
+    policy: EnvPolicy,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .env_policy(policy)
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but runs `program` in `dir` instead of the user's
+/// home directory.
+///
+/// # Errors
+///
+/// Returns an `Err` if the user's environment cannot be captured, as
+/// `cmd_as_user` does. Whether `dir` exists is only checked when the command
+/// is spawned.
 ///
 /// # Examples
 ///
 /// ```no_run
-/// use your_crate_name::try_user_pam_session;
+/// use polyjuice::cmd_as_user_in_dir;
 ///
-/// let username = "example_user".to_string();
-/// match try_pam_session(username) {
-///     Ok(()) => println!("Session created successfully"),
-///     Err(e) => println!("Failed to create session: {}", e),
-/// }
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_in_dir("make", user, "/srv/projects/example");
 /// ```
+pub fn cmd_as_user_in_dir(
+    program: impl AsRef<OsStr>,
+    user: User,
+    dir: impl AsRef<Path>,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .working_dir(dir.as_ref())
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but runs `program` in a session of its own.
 ///
-#[cfg(feature = "pam")]
-pub fn try_pam_session(username: String) -> Result<(), Box<dyn std::error::Error>> {
-    let mut context = Context::new(
-        "polyjuice",     // Service name
-        Some(&username), // Preset username
-        pam_client::conv_null::Conversation::new(),
-    )?;
-    context.acct_mgmt(Flag::NONE)?;
-    let _session = context.open_session(Flag::SILENT)?;
-    Ok(())
+/// A long-running job started with `cmd_as_user` stays in the caller's
+/// session and process group, so it is killed along with the caller by a
+/// Ctrl-C or a hangup. A detached one is not; see `detach`.
+///
+/// # Errors
+///
+/// As for `cmd_as_user`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as_user_detached;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let child = cmd_as_user_detached("Rscript", user)
+///     .unwrap()
+///     .arg("long_job.R")
+///     .spawn()
+///     .unwrap();
+/// ```
+pub fn cmd_as_user_detached(program: impl AsRef<OsStr>, user: User) -> Result<Command, CmdError> {
+    CommandBuilder::new().detached(true).command(program, &user)
+}
+
+/// Like `cmd_as_user`, but runs `program` with the umask `mode`.
+///
+/// Otherwise the child inherits the caller's umask, which for a service
+/// running as root is usually `022` and may not be what the user's login
+/// would set. The mask is set in the child just before exec, so the caller's
+/// own umask is left alone.
+///
+/// # Errors
+///
+/// Returns `CmdError::InvalidUmask` if `mode` is larger than `0o777`, and
+/// `CmdError::FailedGettingEnv` if the environment could not be captured.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as_user_with_umask;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_umask("Rscript", user, 0o077);
+/// ```
+pub fn cmd_as_user_with_umask(
+    program: impl AsRef<OsStr>,
+    user: User,
+    mode: u32,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new().umask(mode).command(program, &user)
+}
+
+/// Like `cmd_as_user`, but gives environment capture `timeout` instead of
+/// `DEFAULT_CAPTURE_TIMEOUT`.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured, with `EnvError::Timeout` inside if it took too long.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::cmd_as_user_with_capture_timeout;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_capture_timeout("R", user, Duration::from_secs(60));
+/// ```
+pub fn cmd_as_user_with_capture_timeout(
+    program: impl AsRef<OsStr>,
+    user: User,
+    timeout: Duration,
+) -> Result<Command, CmdError> {
+    let budget = CaptureBudget {
+        max_duration: timeout,
+        max_bytes: usize::MAX,
+        on_exceeded: BudgetPolicy::Fail,
+    };
+    CommandBuilder::new()
+        .capture_budget(budget)
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but bounds environment capture by `budget`.
+///
+/// Use this for accounts whose login profile cannot be trusted to finish
+/// quickly. See `CaptureBudget` for what happens when the budget runs out.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured, including when the budget ran out under `BudgetPolicy::Fail`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{cmd_as_user_with_capture_budget, BudgetPolicy, CaptureBudget};
+///
+/// let budget = CaptureBudget {
+///     max_duration: Duration::from_secs(5),
+///     max_bytes: 256 * 1024,
+///     on_exceeded: BudgetPolicy::Partial,
+/// };
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_capture_budget("R", user, &budget);
+/// ```
+pub fn cmd_as_user_with_capture_budget(
+    program: impl AsRef<OsStr>,
+    user: User,
+    budget: &CaptureBudget,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .capture_budget(*budget)
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but captures the environment with `fallback_shell`
+/// if the user's login shell is missing or not executable.
+///
+/// `cmd_as_user` falls back to `DEFAULT_FALLBACK_SHELL` (`/bin/sh`); use
+/// this to pick another one. Only the capture is affected, the command
+/// itself runs `program` directly.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as_user_with_fallback_shell;
+///
+/// let user = users::get_user_by_name("migrated_user").unwrap();
+/// let cmd = cmd_as_user_with_fallback_shell("R", user, "/bin/bash");
+/// ```
+pub fn cmd_as_user_with_fallback_shell(
+    program: impl AsRef<OsStr>,
+    user: User,
+    fallback_shell: impl AsRef<Path>,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .fallback_shell(fallback_shell.as_ref())
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but captures the environment through `backend`.
+///
+/// See `EnvBackend` for how the backends differ.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured; the inner error names the backend that was tried.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_backend, EnvBackend};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_backend("R", user, EnvBackend::Runuser);
+/// ```
+pub fn cmd_as_user_with_backend(
+    program: impl AsRef<OsStr>,
+    user: User,
+    backend: EnvBackend,
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .backend(backend)
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but also passes the caller's variables named in
+/// `vars` through to the child.
+///
+/// The named variables are copied after the user's environment is applied,
+/// so they win over the user's value; variables the caller does not have
+/// are skipped. See `PassthroughVars` for the pattern syntax.
+///
+/// # Errors
+///
+/// As for `cmd_as_user`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as_user_with_passthrough;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let vars = ["SSH_AUTH_SOCK", "DISPLAY", "XAUTHORITY", "TERM", "LC_*"];
+/// let cmd = cmd_as_user_with_passthrough("git", user, &vars);
+/// ```
+pub fn cmd_as_user_with_passthrough(
+    program: impl AsRef<OsStr>,
+    user: User,
+    vars: &[&str],
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .passthrough(vars)
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but keeps the caller's variables named in `preserve`
+/// unless the user's environment sets them too.
+///
+/// This is the reverse precedence of `cmd_as_user_with_passthrough`: the
+/// preserved variables only fill in what the user's environment lacks, so
+/// e.g. a `TZ` from the user's profile is kept while the caller's `DISPLAY`
+/// comes through. Use `cmd_as_user_with_passthrough` when the caller's value
+/// should win. `preserve` takes the same patterns as `PassthroughVars`.
+///
+/// # Errors
+///
+/// As for `cmd_as_user`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as_user_preserving;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_preserving("xterm", user, &["TERM", "DISPLAY", "SSH_AUTH_SOCK", "TZ"]);
+/// ```
+pub fn cmd_as_user_preserving(
+    program: impl AsRef<OsStr>,
+    user: User,
+    preserve: &[&str],
+) -> Result<Command, CmdError> {
+    CommandBuilder::new()
+        .preserve_env(preserve)
+        .command(program, &user)
+}
+
+/// Like `cmd_as_user`, but also writes the captured environment to an `EnvFile`
+/// and passes its path to the child in `POLYJUICE_ENV_FILE`.
+///
+/// This is for wrapper scripts that want to re-source the user's environment,
+/// e.g. in subshells that start from a clean slate. The file is deleted when
+/// the returned `EnvFile` is dropped, so hold on to it until the child exits.
+/// Variables that are not valid UTF-8 are passed to the child but left out
+/// of the file.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be captured
+/// and `CmdError::FailedWritingEnvFile` if the file could not be written.
+pub fn cmd_as_user_with_env_file(
+    program: impl AsRef<OsStr>,
+    user: User,
+) -> Result<(Command, EnvFile), CmdError> {
+    let builder = CommandBuilder::new();
+    let env = builder.environment(&user)?;
+    let utf8_env = env
+        .iter()
+        .filter_map(|(key, value)| Some((key.to_str()?.to_owned(), value.to_str()?.to_owned())))
+        .collect();
+    let env_file = EnvFile::write(&user, &utf8_env).map_err(CmdError::FailedWritingEnvFile)?;
+    let mut new_cmd = builder.command_with_env(program, &user, &env)?;
+    env_file.apply(&mut new_cmd);
+    Ok((new_cmd, env_file))
+}
+
+/// Like `cmd_as_user`, but only if `policy` permits `user` to run `program`.
+///
+/// The program is resolved against the `PATH` from the user's environment
+/// (the one the child would search) and the returned command executes the
+/// resolved absolute path, so what runs is exactly what was checked. See
+/// `ProgramPolicy` for how allow and deny rules combine.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be captured,
+/// `CmdError::ProgramNotFound` if the program does not resolve to an executable,
+/// and `CmdError::ProgramNotPermitted` if the policy rejects it.
+pub fn cmd_as_user_with_program_policy(
+    program: impl AsRef<OsStr>,
+    user: User,
+    policy: &ProgramPolicy,
+) -> Result<Command, CmdError> {
+    let builder = CommandBuilder::new();
+    let env = builder.environment(&user)?;
+    let search_path = env.get(OsStr::new("PATH")).map(OsString::as_os_str);
+    let resolved = policy.check(&user, program, search_path)?;
+    builder.command_with_env(resolved, &user, &env)
+}
+
+/// Like `cmd_as_user`, but runs with the basic environment from
+/// `get_minimal_user_env` instead of the user's login environment.
+///
+/// Nothing is spawned to build the command, so this neither needs root nor
+/// depends on the user's login profile; see `get_minimal_user_env` for what
+/// the child does not get as a result. Spawning the command still needs the
+/// privileges to switch to `user`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::cmd_as_user_minimal_env;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let status = cmd_as_user_minimal_env("ls", &user).status();
+/// ```
+pub fn cmd_as_user_minimal_env(program: impl AsRef<OsStr>, user: &User) -> Command {
+    user_command(program, user, &get_minimal_user_env(user))
+}
+
+/// Like `cmd_as_user`, but runs with `env` instead of a freshly captured
+/// environment.
+///
+/// Capturing runs the user's whole login stack, which is slow. When many
+/// commands are launched as the same user, capture once with `get_user_env`
+/// and build each command with this instead. As with `cmd_as_user`, the
+/// caller's environment is cleared first, so the child sees exactly `env`.
+///
+/// This also decouples capture from launch: an environment recorded earlier
+/// (see `save_env_snapshot` and `load_env_snapshot`) can be replayed to
+/// reproduce what a command saw at that time. Only the environment is
+/// replayed; the uid, gid and groups always come from the live `user` entry.
+///
+/// Paths in a replayed environment may have gone away since it was recorded.
+/// `HOME`, `PWD` and `SHELL` and each `PATH` entry that no longer exist are
+/// reported with a warning through the `log` crate; the command is built
+/// regardless.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_env, get_user_env};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let env = get_user_env(user.name()).unwrap();
+/// for job in ["a.R", "b.R", "c.R"] {
+///     cmd_as_user_with_env("Rscript", &user, env.as_map())
+///         .arg(job)
+///         .status()
+///         .unwrap();
+/// }
+/// ```
+pub fn cmd_as_user_with_env(
+    program: impl AsRef<OsStr>,
+    user: &User,
+    env: &HashMap<String, String>,
+) -> Command {
+    warn_missing_paths(env);
+    user_command(program, user, env)
+}
+
+fn warn_missing_paths(env: &HashMap<String, String>) {
+    for key in ["HOME", "PWD", "SHELL"] {
+        if let Some(value) = env.get(key) {
+            if !Path::new(value).exists() {
+                log::warn!("{} in replayed environment does not exist: {}", key, value);
+            }
+        }
+    }
+    if let Some(path) = env.get("PATH") {
+        for dir in std::env::split_paths(path) {
+            if !dir.as_os_str().is_empty() && !dir.exists() {
+                log::warn!(
+                    "PATH entry in replayed environment does not exist: {}",
+                    dir.display()
+                );
+            }
+        }
+    }
+}
+
+fn user_command<K, V>(
+    program: impl AsRef<OsStr>,
+    user: &User,
+    env: impl IntoIterator<Item = (K, V)>,
+) -> Command
+where
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    let drop = PrivilegeDrop::to_user(user, GroupPolicy::default());
+    let drop = match home_dir(user) {
+        Some(home) => drop.in_dir(home),
+        None => drop,
+    };
+    user_command_with_drop(Command::new(program), env, drop)
+}
+
+/// Sets `new_cmd` up to run as the user of `drop` with exactly `env`.
+///
+/// `pre_exec` hooks run in the order they are added, so anything already
+/// added to `new_cmd` runs before the privilege drop and anything added
+/// later runs after it.
+fn user_command_with_drop<K, V>(
+    mut new_cmd: Command,
+    env: impl IntoIterator<Item = (K, V)>,
+    drop: PrivilegeDrop,
+) -> Command
+where
+    K: AsRef<OsStr>,
+    V: AsRef<OsStr>,
+{
+    drop.apply(&mut new_cmd);
+    new_cmd.env_clear().envs(env);
+    new_cmd
+}
+
+/// The home directory of `user` for a command to start in, if there is one.
+fn home_dir(user: &User) -> Option<&Path> {
+    let home = user.home_dir();
+    if home.is_dir() {
+        Some(home)
+    } else {
+        log::warn!(
+            "Home directory {} of {} is not a directory, keeping the current working directory",
+            home.display(),
+            user.name().to_string_lossy()
+        );
+        None
+    }
 }
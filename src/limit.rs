@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::process::{Command, ExitStatus};
+use std::sync::{Arc, Condvar, Mutex};
+
+use users::User;
+
+use crate::CmdError;
+
+/// What `ConcurrencyLimiter` does when a user is already at the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitMode {
+    /// Wait until one of the user's running launches finishes.
+    Block,
+    /// Fail straight away with `CmdError::ConcurrencyLimitReached`.
+    Reject,
+}
+
+#[derive(Debug)]
+struct Slots {
+    max_per_user: usize,
+    mode: LimitMode,
+    running: Mutex<HashMap<u32, usize>>,
+    freed: Condvar,
+}
+
+/// Caps how many launches each user may have running at once.
+///
+/// Limits are counted per uid and shared by every clone of the limiter, so
+/// one limiter can be handed to all the threads (or request handlers) of a
+/// launcher service. A launch holds its slot through a `LaunchSlot`, which
+/// gives it back when dropped. That happens however the launch ends:
+/// normal exit, the child being killed, or an error while spawning or
+/// waiting.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user, ConcurrencyLimiter, LimitMode};
+///
+/// let limiter = ConcurrencyLimiter::new(4, LimitMode::Reject);
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let mut cmd = cmd_as_user("R", user.clone()).unwrap();
+/// match limiter.run(&user, &mut cmd) {
+///     Ok(status) => println!("exited with {}", status),
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    slots: Arc<Slots>,
+}
+
+impl ConcurrencyLimiter {
+    /// Allows up to `max_per_user` concurrent launches for each user.
+    pub fn new(max_per_user: usize, mode: LimitMode) -> Self {
+        ConcurrencyLimiter {
+            slots: Arc::new(Slots {
+                max_per_user,
+                mode,
+                running: Mutex::new(HashMap::new()),
+                freed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Takes one of `user`'s slots, waiting for one to free up in
+    /// `LimitMode::Block`.
+    ///
+    /// Hold on to the returned slot for as long as the launch runs, e.g.
+    /// next to the `Child` of a spawned command.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CmdError::ConcurrencyLimitReached` in `LimitMode::Reject` if
+    /// the user has no slot left. A limit of zero rejects in either mode,
+    /// since no slot will ever free up.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use polyjuice::{CmdError, ConcurrencyLimiter, LimitMode};
+    ///
+    /// let limiter = ConcurrencyLimiter::new(1, LimitMode::Reject);
+    /// let user = users::User::new(4242, "example_user", 4242);
+    ///
+    /// let slot = limiter.acquire(&user).unwrap();
+    /// assert!(matches!(
+    ///     limiter.acquire(&user),
+    ///     Err(CmdError::ConcurrencyLimitReached(4242, 1))
+    /// ));
+    /// drop(slot);
+    /// assert!(limiter.acquire(&user).is_ok());
+    /// ```
+    pub fn acquire(&self, user: &User) -> Result<LaunchSlot, CmdError> {
+        let uid = user.uid();
+        let limit = self.slots.max_per_user;
+        let mut running = self.slots.running.lock().unwrap_or_else(|e| e.into_inner());
+        while running.get(&uid).copied().unwrap_or(0) >= limit {
+            if self.slots.mode == LimitMode::Reject || limit == 0 {
+                return Err(CmdError::ConcurrencyLimitReached(uid, limit));
+            }
+            running = self
+                .slots
+                .freed
+                .wait(running)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        *running.entry(uid).or_insert(0) += 1;
+        Ok(LaunchSlot {
+            slots: Arc::clone(&self.slots),
+            uid,
+        })
+    }
+
+    /// Runs `cmd` to completion within one of `user`'s slots.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CmdError::ConcurrencyLimitReached` as for `acquire`, and
+    /// `CmdError::SpawnFailed` if the command could not be spawned or
+    /// waited on.
+    pub fn run(&self, user: &User, cmd: &mut Command) -> Result<ExitStatus, CmdError> {
+        let _slot = self.acquire(user)?;
+        cmd.status().map_err(CmdError::SpawnFailed)
+    }
+
+    /// How many launches `user` currently has running under this limiter.
+    pub fn running(&self, user: &User) -> usize {
+        let running = self.slots.running.lock().unwrap_or_else(|e| e.into_inner());
+        running.get(&user.uid()).copied().unwrap_or(0)
+    }
+}
+
+/// A slot taken from a `ConcurrencyLimiter`, released on drop.
+#[derive(Debug)]
+pub struct LaunchSlot {
+    slots: Arc<Slots>,
+    uid: u32,
+}
+
+impl Drop for LaunchSlot {
+    fn drop(&mut self) {
+        let mut running = self.slots.running.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = running.get_mut(&self.uid) {
+            *count -= 1;
+            if *count == 0 {
+                running.remove(&self.uid);
+            }
+        }
+        self.slots.freed.notify_all();
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Where the system-wide locale is configured: Debian and Ubuntu use the
+/// first, systemd-based distributions the second. The first one found is
+/// read.
+pub const SYSTEM_LOCALE_FILES: &[&str] = &["/etc/default/locale", "/etc/locale.conf"];
+
+/// Reads the locale variables (`LANG`, `LANGUAGE` and `LC_*`) set in a
+/// locale file such as `/etc/default/locale`.
+///
+/// These files hold `NAME=value` lines, the value optionally quoted, as
+/// read by `pam_env` and `systemd`. Comments, blank lines and any other
+/// variables are skipped.
+///
+/// # Errors
+///
+/// Returns the error of reading `path`.
+///
+/// # Examples
+///
+/// ```
+/// use polyjuice::load_locale_file;
+///
+/// let path = std::env::temp_dir().join(format!("polyjuice-locale-{}", std::process::id()));
+/// let contents = "# set by the installer\nLANG=\"en_US.UTF-8\"\nLC_TIME=en_GB.UTF-8\nPATH=/bin\n";
+/// std::fs::write(&path, contents).unwrap();
+/// let locale = load_locale_file(&path).unwrap();
+/// # std::fs::remove_file(&path).unwrap();
+/// assert_eq!(locale.len(), 2);
+/// assert_eq!(locale["LANG"], "en_US.UTF-8");
+/// assert_eq!(locale["LC_TIME"], "en_GB.UTF-8");
+/// ```
+pub fn load_locale_file(path: impl AsRef<Path>) -> io::Result<HashMap<String, String>> {
+    let contents = fs::read_to_string(path)?;
+    let locale = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| is_locale_var(key))
+        .map(|(key, value)| (key.to_string(), unquote(value.trim()).to_string()))
+        .collect();
+    Ok(locale)
+}
+
+/// The locale variables of the first of `SYSTEM_LOCALE_FILES` that can be
+/// read, or none.
+pub(crate) fn system_locale() -> HashMap<String, String> {
+    for file in SYSTEM_LOCALE_FILES {
+        match load_locale_file(file) {
+            Ok(locale) => return locale,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to read locale file {}: {}", file, e),
+        }
+    }
+    HashMap::new()
+}
+
+fn is_locale_var(name: &str) -> bool {
+    name == "LANG" || name == "LANGUAGE" || name.starts_with("LC_")
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return inner;
+        }
+    }
+    value
+}
@@ -0,0 +1,172 @@
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use users::User;
+
+use crate::CmdError;
+
+/// How long the username based entry points wait on NSS before giving up.
+pub const DEFAULT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Looks up a user by name, giving up after `timeout`.
+///
+/// `getpwnam` has no timeout of its own and will block for as long as a
+/// network backed NSS module (LDAP, SSSD) waits on its server. The lookup is
+/// therefore run on a helper thread; if it has not answered before the
+/// deadline the thread is abandoned and `CmdError::LookupTimeout` returned.
+/// An abandoned thread finishes (or keeps hanging) in the background and its
+/// result is discarded.
+///
+/// # Errors
+///
+/// Returns `CmdError::UserNotFound` if the lookup completes without a match
+/// and `CmdError::LookupTimeout` if it does not complete in time.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::get_user_by_name_with_timeout;
+///
+/// let user = get_user_by_name_with_timeout("example_user", Duration::from_secs(2));
+/// ```
+pub fn get_user_by_name_with_timeout(
+    username: impl AsRef<OsStr>,
+    timeout: Duration,
+) -> Result<User, CmdError> {
+    let username = username.as_ref().to_os_string();
+    with_timeout(move || users::get_user_by_name(&username), timeout)
+}
+
+/// Looks up a user by uid, giving up after `timeout`.
+///
+/// See `get_user_by_name_with_timeout`; a missing user is reported as
+/// `CmdError::UidNotFound` instead.
+pub fn get_user_by_uid_with_timeout(uid: u32, timeout: Duration) -> Result<User, CmdError> {
+    with_timeout(move || users::get_user_by_uid(uid), timeout).map_err(|e| match e {
+        CmdError::UserNotFound => CmdError::UidNotFound(uid),
+        e => e,
+    })
+}
+
+fn with_timeout(
+    lookup: impl FnOnce() -> Option<User> + Send + Clone + 'static,
+    timeout: Duration,
+) -> Result<User, CmdError> {
+    let (tx, rx) = mpsc::channel();
+    let threaded = lookup.clone();
+    let spawned = thread::Builder::new()
+        .name("polyjuice-nss-lookup".to_string())
+        .spawn(move || {
+            // the receiver is gone if we already timed out
+            let _ = tx.send(threaded());
+        });
+    if spawned.is_err() {
+        // without a helper thread there is no way to bound the lookup
+        return lookup().ok_or(CmdError::UserNotFound);
+    }
+
+    match rx.recv_timeout(timeout) {
+        Ok(user) => user.ok_or(CmdError::UserNotFound),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(CmdError::LookupTimeout(timeout)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(CmdError::UserNotFound),
+    }
+}
+
+/// The gids of every group `user` belongs to: the primary group first, then
+/// each group that lists the user as a member.
+///
+/// `users::get_user_groups` is not used because it does not truncate the
+/// buffer it hands to `getgrouplist`, so its result always includes group 0
+/// (`root`) from the unused, zeroed tail.
+pub(crate) fn user_group_ids(user: &User) -> Vec<u32> {
+    let gid = user.primary_group_id();
+    let Ok(name) = CString::new(user.name().as_bytes()) else {
+        return vec![gid];
+    };
+    let mut groups: Vec<libc::gid_t> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let res =
+            unsafe { libc::getgrouplist(name.as_ptr(), gid, groups.as_mut_ptr(), &mut count) };
+        if res >= 0 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+        // count now holds the number needed, if the libc reports it
+        let needed = (count as usize).max(groups.len() * 2);
+        if needed > 1 << 20 {
+            return vec![gid];
+        }
+        groups.resize(needed, 0);
+    }
+}
+
+/// A user given either by login name or by numeric uid.
+///
+/// Identifiers often arrive as a string that may hold either (a CLI argument,
+/// a field in a job description). Strings convert to `UserIdent::Name` and
+/// are disambiguated when resolved:
+///
+/// - a string that names an existing user refers to that user, even if it is
+///   all digits;
+/// - otherwise a string of digits is taken as a uid.
+///
+/// So on a system with a user literally named `1000`, `"1000"` refers to
+/// that user and not to uid 1000. Use `UserIdent::Uid(1000)` (or the `u32`
+/// conversion) to always mean the uid.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UserIdent {
+    Name(String),
+    Uid(u32),
+}
+
+impl UserIdent {
+    /// Looks up the user this identifies, bounding each NSS query by
+    /// `DEFAULT_LOOKUP_TIMEOUT`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CmdError::UserNotFound` if no user matches (`CmdError::UidNotFound`
+    /// for a `UserIdent::Uid`) and `CmdError::LookupTimeout` if NSS does not
+    /// answer in time.
+    pub fn resolve(&self) -> Result<User, CmdError> {
+        match self {
+            UserIdent::Uid(uid) => get_user_by_uid_with_timeout(*uid, DEFAULT_LOOKUP_TIMEOUT),
+            UserIdent::Name(name) => {
+                match get_user_by_name_with_timeout(name, DEFAULT_LOOKUP_TIMEOUT) {
+                    Err(CmdError::UserNotFound) => match name.parse() {
+                        Ok(uid) => get_user_by_uid_with_timeout(uid, DEFAULT_LOOKUP_TIMEOUT)
+                            .map_err(|e| match e {
+                                CmdError::UidNotFound(_) => CmdError::UserNotFound,
+                                e => e,
+                            }),
+                        Err(_) => Err(CmdError::UserNotFound),
+                    },
+                    found => found,
+                }
+            }
+        }
+    }
+}
+
+impl From<&str> for UserIdent {
+    fn from(name: &str) -> Self {
+        UserIdent::Name(name.to_string())
+    }
+}
+
+impl From<String> for UserIdent {
+    fn from(name: String) -> Self {
+        UserIdent::Name(name)
+    }
+}
+
+impl From<u32> for UserIdent {
+    fn from(uid: u32) -> Self {
+        UserIdent::Uid(uid)
+    }
+}
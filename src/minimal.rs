@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::fs;
+
+use users::os::unix::UserExt;
+use users::User;
+
+/// `PATH` for regular users when `/etc/login.defs` does not set `ENV_PATH`.
+pub const DEFAULT_USER_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// `PATH` for root when `/etc/login.defs` does not set `ENV_SUPATH`.
+pub const DEFAULT_ROOT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+const LOGIN_DEFS: &str = "/etc/login.defs";
+
+/// Builds a basic environment for `user` from its passwd entry, without
+/// running anything.
+///
+/// The result holds `HOME`, `USER`, `LOGNAME` and `SHELL` as `login` would
+/// set them, and `PATH` from `ENV_PATH` in `/etc/login.defs` (`ENV_SUPATH`
+/// for root), or `DEFAULT_USER_PATH` / `DEFAULT_ROOT_PATH` if it has none.
+///
+/// Unlike `get_user_env` this needs no privileges and never forks, so it is
+/// cheap enough to call for every launch. The price is that nothing from the
+/// user's login profile is included: no `PATH` additions, no locale, no
+/// variables set by PAM modules. Use it for programs that only need to know
+/// whose they are and where to find the standard tools.
+///
+/// # Examples
+///
+/// ```
+/// use polyjuice::get_minimal_user_env;
+/// use users::os::unix::UserExt;
+///
+/// let user = users::User::new(4242, "example_user", 4242)
+///     .with_home_dir("/home/example_user")
+///     .with_shell("/bin/bash");
+/// let env = get_minimal_user_env(&user);
+/// assert_eq!(env["HOME"], "/home/example_user");
+/// assert_eq!(env["USER"], "example_user");
+/// assert_eq!(env["LOGNAME"], "example_user");
+/// assert_eq!(env["SHELL"], "/bin/bash");
+/// assert!(env["PATH"].contains("/bin"));
+/// ```
+pub fn get_minimal_user_env(user: &User) -> HashMap<String, String> {
+    let name = user.name().to_string_lossy().to_string();
+    let shell = match user.shell().as_os_str().is_empty() {
+        // an empty shell field means /bin/sh
+        true => "/bin/sh".to_string(),
+        false => user.shell().to_string_lossy().to_string(),
+    };
+    let path = match user.uid() {
+        0 => login_defs_path("ENV_SUPATH").unwrap_or_else(|| DEFAULT_ROOT_PATH.to_string()),
+        _ => login_defs_path("ENV_PATH").unwrap_or_else(|| DEFAULT_USER_PATH.to_string()),
+    };
+    HashMap::from([
+        (
+            "HOME".to_string(),
+            user.home_dir().to_string_lossy().to_string(),
+        ),
+        ("USER".to_string(), name.clone()),
+        ("LOGNAME".to_string(), name),
+        ("SHELL".to_string(), shell),
+        ("PATH".to_string(), path),
+    ])
+}
+
+/// Reads `key` from `/etc/login.defs`, whose path settings may be written
+/// either as `ENV_PATH PATH=/bin:...` or as `ENV_PATH /bin:...`.
+fn login_defs_path(key: &str) -> Option<String> {
+    let defs = fs::read_to_string(LOGIN_DEFS).ok()?;
+    // a later setting wins, as in login
+    defs.lines()
+        .rev()
+        .filter_map(|line| line.trim().split_once(char::is_whitespace))
+        .filter(|(k, _)| *k == key)
+        .map(|(_, value)| value.trim())
+        .map(|value| value.strip_prefix("PATH=").unwrap_or(value).to_string())
+        .find(|value| !value.is_empty())
+}
@@ -0,0 +1,305 @@
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+
+use users::User;
+
+use crate::{cmd_as_user, cmd_as_username, CmdError};
+
+/// Which of the child's output streams a line came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    Stdout,
+    Stderr,
+}
+
+impl Display for StreamKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamKind::Stdout => write!(f, "stdout"),
+            StreamKind::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// Runs `program` with `args` as `user` to completion and returns its
+/// captured output.
+///
+/// This is `cmd_as_user` followed by `Command::output`, for the common case
+/// of a command that needs no further setup. The child's stdin is empty.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured and `CmdError::SpawnFailed` if the command could not be started
+/// or waited on. A command that runs but exits unsuccessfully is not an
+/// error; check `Output::status`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::run_as_user;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let output = run_as_user("Rscript", &["-e", "cat(R.version.string)"], &user).unwrap();
+/// println!("{}", String::from_utf8_lossy(&output.stdout));
+/// ```
+pub fn run_as_user<S: AsRef<OsStr>>(
+    program: impl AsRef<OsStr>,
+    args: &[S],
+    user: &User,
+) -> Result<Output, CmdError> {
+    let mut cmd = cmd_as_user(program, user.clone())?;
+    cmd.args(args).output().map_err(CmdError::SpawnFailed)
+}
+
+/// Like `run_as_user`, but an unsuccessful exit is an error too.
+///
+/// For commands whose output is only of use when they succeed. On success
+/// the output is returned as from `run_as_user`, with a successful status.
+///
+/// # Errors
+///
+/// As for `run_as_user`, and `CmdError::CommandFailed` with the exit status
+/// and captured stderr if the command exits unsuccessfully or is killed by a
+/// signal. The stderr is included in the error's message.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{run_as_user_checked, CmdError};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// match run_as_user_checked("id", &["-u"], &user) {
+///     Ok(output) => {
+///         let uid = String::from_utf8(output.stdout).unwrap();
+///         assert_eq!(uid.trim(), user.uid().to_string());
+///     }
+///     Err(CmdError::CommandFailed(status, _)) => eprintln!("id exited with {}", status),
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+pub fn run_as_user_checked<S: AsRef<OsStr>>(
+    program: impl AsRef<OsStr>,
+    args: &[S],
+    user: &User,
+) -> Result<Output, CmdError> {
+    let output = run_as_user(program, args, user)?;
+    if !output.status.success() {
+        return Err(CmdError::CommandFailed(output.status, output.stderr));
+    }
+    Ok(output)
+}
+
+/// Like `run_as_user`, looking the user up by name as `cmd_as_username` does.
+///
+/// # Errors
+///
+/// As for `cmd_as_username` and `run_as_user`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::run_as_username;
+///
+/// let output = run_as_username("ls", &["-l"], "example_user").unwrap();
+/// assert!(output.status.success());
+/// ```
+pub fn run_as_username<S: AsRef<OsStr>>(
+    program: impl AsRef<OsStr>,
+    args: &[S],
+    username: impl AsRef<OsStr>,
+) -> Result<Output, CmdError> {
+    let mut cmd = cmd_as_username(program, username)?;
+    cmd.args(args).output().map_err(CmdError::SpawnFailed)
+}
+
+/// Runs `program` with `args` as `user` and calls `on_line` for every line
+/// it writes to stdout or stderr.
+///
+/// Both streams are read on helper threads, and `on_line` is called on the
+/// calling thread, one line at a time in the order they were read, without
+/// the trailing newline. Output that is not valid UTF-8 is converted
+/// lossily. The child's stdin is inherited. Returns once the child has
+/// exited and both streams have been drained, so no line is lost.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured and `CmdError::SpawnFailed` if the command could not be started,
+/// read from or waited on.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{run_as_user_streaming, StreamKind};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let status = run_as_user_streaming("make", &["-j4"], &user, |kind, line| match kind {
+///     StreamKind::Stdout => log::info!("make: {}", line),
+///     StreamKind::Stderr => log::warn!("make: {}", line),
+/// })
+/// .unwrap();
+/// ```
+pub fn run_as_user_streaming<S: AsRef<OsStr>>(
+    program: impl AsRef<OsStr>,
+    args: &[S],
+    user: &User,
+    on_line: impl FnMut(StreamKind, &str),
+) -> Result<ExitStatus, CmdError> {
+    let mut cmd = cmd_as_user(program, user.clone())?;
+    cmd.args(args);
+    stream_lines(&mut cmd, on_line)
+}
+
+/// Spawns `cmd` with piped stdout and stderr and calls `on_line` for each
+/// line, as described for `run_as_user_streaming`.
+pub(crate) fn stream_lines(
+    cmd: &mut Command,
+    mut on_line: impl FnMut(StreamKind, &str),
+) -> Result<ExitStatus, CmdError> {
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(CmdError::SpawnFailed)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    let (tx, rx) = mpsc::channel();
+    let read = thread::scope(|scope| {
+        let out_tx = tx.clone();
+        let out = scope.spawn(move || send_lines(StreamKind::Stdout, stdout, out_tx));
+        let err = scope.spawn(move || send_lines(StreamKind::Stderr, stderr, tx));
+        // ends once both readers hit EOF and drop their senders
+        for (kind, line) in rx {
+            on_line(kind, &line);
+        }
+        out.join()
+            .expect("stdout reader panicked")
+            .and(err.join().expect("stderr reader panicked"))
+    });
+    let status = child.wait().map_err(CmdError::SpawnFailed)?;
+    read.map_err(CmdError::SpawnFailed)?;
+    Ok(status)
+}
+
+fn send_lines(
+    kind: StreamKind,
+    stream: impl Read,
+    tx: mpsc::Sender<(StreamKind, String)>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let text = String::from_utf8_lossy(&line);
+        let text = text.strip_suffix('\n').unwrap_or(&text).to_string();
+        // the receiver only goes away once both readers are done
+        let _ = tx.send((kind, text));
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Runs `cmd` and copies its stdout and stderr to `sink` line by line, each
+/// line preceded by a prefix.
+///
+/// `prefix` is called for every line with the stream it came from and the
+/// child's pid, so it can produce fixed tags (`[stdout] `) as well as
+/// per-line ones such as timestamps. Both streams are read concurrently and
+/// each line is written to `sink` in one piece, so lines from the two streams
+/// interleave but never mix. Output that is not valid UTF-8 is converted
+/// lossily.
+///
+/// The child's stdout and stderr are replaced with pipes; stdin is left as
+/// configured on `cmd`. Returns once the child has exited and both streams
+/// have been drained.
+///
+/// # Errors
+///
+/// Returns an error if the command cannot be spawned or waited on, or if
+/// reading its output or writing to `sink` fails.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_username, run_prefixed};
+///
+/// let mut cmd = cmd_as_username("R", "example_user").unwrap();
+/// cmd.arg("-e").arg("print('hello')");
+/// let status = run_prefixed(&mut cmd, |kind, _pid| format!("[{}] ", kind), std::io::stdout());
+/// ```
+pub fn run_prefixed<W: Write + Send>(
+    cmd: &mut Command,
+    prefix: impl Fn(StreamKind, u32) -> String + Sync,
+    sink: W,
+) -> io::Result<ExitStatus> {
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let pid = child.id();
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    let sink = Mutex::new(sink);
+    let (out, err) = thread::scope(|scope| {
+        let out = scope.spawn(|| copy_lines(StreamKind::Stdout, stdout, pid, &prefix, &sink));
+        let err = copy_lines(StreamKind::Stderr, stderr, pid, &prefix, &sink);
+        (out.join().expect("stdout reader panicked"), err)
+    });
+    let status = child.wait()?;
+    out?;
+    err?;
+    sink.into_inner()
+        .unwrap_or_else(|e| e.into_inner())
+        .flush()?;
+    Ok(status)
+}
+
+fn copy_lines<W: Write>(
+    kind: StreamKind,
+    stream: impl Read,
+    pid: u32,
+    prefix: &impl Fn(StreamKind, u32) -> String,
+    sink: &Mutex<W>,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    let mut written = Ok(());
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        // keep draining after a failed write so the child cannot block on a full pipe
+        if written.is_ok() {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.strip_suffix('\n').unwrap_or(&text);
+            let mut sink = sink.lock().unwrap_or_else(|e| e.into_inner());
+            written = writeln!(sink, "{}{}", prefix(kind, pid), text);
+        }
+        line.clear();
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_run_reports_the_uid_and_failures() {
+        if users::get_effective_uid() != 0 {
+            // capturing the environment needs root
+            return;
+        }
+        let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+        let output = run_as_user_checked("id", &["-u"], &user).unwrap();
+        let uid = String::from_utf8(output.stdout).unwrap();
+        assert_eq!(uid.trim(), user.uid().to_string());
+
+        let failed = run_as_user_checked("sh", &["-c", "echo oops >&2; exit 3"], &user);
+        assert!(matches!(
+            failed,
+            Err(CmdError::CommandFailed(status, stderr))
+                if status.code() == Some(3) && stderr == b"oops\n"
+        ));
+    }
+}
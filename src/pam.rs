@@ -0,0 +1,669 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fmt::Display;
+use std::io;
+use std::process::ExitStatus;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use pam_client::env_list::EnvList;
+use pam_client::{Context, ConversationHandler, ErrorCode, Flag, Session, SessionToken};
+
+use crate::env::validate_username;
+use crate::{get_user_by_name_with_timeout, CmdError, CommandBuilder, DEFAULT_LOOKUP_TIMEOUT};
+
+/// The PAM service polyjuice uses unless told otherwise, configured in
+/// `/etc/pam.d/polyjuice`.
+///
+/// Without that file, PAM falls back to the `other` service, which commonly
+/// denies everything. To avoid shipping one, pass the name of a stack the
+/// system already has to `try_pam_session_with_service`; see there.
+pub const DEFAULT_PAM_SERVICE: &str = "polyjuice";
+
+/// Why a PAM session could not be set up.
+///
+/// The step that failed is told apart so callers can react differently,
+/// e.g. retry when opening the session failed with `SYSTEM_ERR` but give up
+/// when account management reports `ACCT_EXPIRED`. Errors from the PAM stack
+/// name the service they went through, since a misconfigured or missing
+/// service file is the usual cause.
+#[derive(Debug)]
+pub enum PamError {
+    /// Authentication (`pam_authenticate`) failed for another reason than a
+    /// wrong password, e.g. `MAXTRIES` or `AUTHINFO_UNAVAIL`.
+    Authenticate(String, ErrorCode),
+    /// The service rejected the password (`AUTH_ERR`).
+    WrongPassword(String),
+    /// The PAM context could not be created, e.g. the service is not configured.
+    ContextInit(String, pam_client::Error),
+    /// Account management (`pam_acct_mgmt`) of the service rejected the user.
+    AcctMgmt(String, ErrorCode),
+    /// The service could not open the session (`pam_open_session`).
+    OpenSession(String, ErrorCode),
+    Timeout(Duration),
+    Thread(io::Error),
+    /// An empty PAM service name was given.
+    EmptyService,
+    /// A PAM service name containing a path separator was given.
+    InvalidService(String),
+    /// `try_pam_session_with_retry` made the given number of attempts, the
+    /// last one failing with the inner error.
+    RetriesExhausted(u32, Box<PamError>),
+}
+
+impl Display for PamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PamError::Authenticate(service, code) => {
+                write!(
+                    f,
+                    "PAM authentication with service {} failed: {:?}",
+                    service, code
+                )
+            }
+            PamError::WrongPassword(service) => {
+                write!(f, "PAM service {} rejected the password", service)
+            }
+            PamError::ContextInit(service, e) => {
+                write!(f, "Failed initializing PAM service {}: {}", service, e)
+            }
+            PamError::AcctMgmt(service, code) => {
+                write!(
+                    f,
+                    "PAM account check of service {} failed: {:?}",
+                    service, code
+                )
+            }
+            PamError::OpenSession(service, code) => {
+                write!(
+                    f,
+                    "Failed opening PAM session of service {}: {:?}",
+                    service, code
+                )
+            }
+            PamError::Timeout(t) => write!(f, "PAM session did not open within {:?}", t),
+            PamError::Thread(e) => write!(f, "Failed running PAM session thread: {}", e),
+            PamError::EmptyService => write!(f, "PAM service name is empty"),
+            PamError::InvalidService(service) => {
+                write!(f, "PAM service name {:?} must not contain '/'", service)
+            }
+            PamError::RetriesExhausted(attempts, e) => {
+                write!(f, "Giving up on PAM after {} attempts: {}", attempts, e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PamError::ContextInit(_, e) => Some(e),
+            PamError::Thread(e) => Some(e),
+            PamError::RetriesExhausted(_, e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Attempts to create a PAM session for a specified user.
+///
+/// This function initializes a PAM context for the given username and tries to
+/// open a session. It's intended for authentication and session management
+/// using PAM (Pluggable Authentication Modules).
+///
+/// This is particularly useful to prompt PAM to activated session related triggers
+/// such as pam_mkhomedir
+///
+/// # Parameters
+///
+/// * `username`: The username for which to create the PAM session. This should
+///   be a valid username on the system.
+///
+/// # Returns
+///
+/// If successful, returns `Ok(())`. On failure, returns a `PamError` naming
+/// the step that failed.
+///
+/// # Errors
+///
+/// Returns:
+///
+/// - `PamError::ContextInit` if the PAM context cannot be initialized (e.g., if
+///   the provided username is invalid).
+/// - `PamError::AcctMgmt` if the account management step (`acct_mgmt`) fails.
+/// - `PamError::OpenSession` if the session cannot be opened.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::try_pam_session;
+///
+/// let username = "example_user".to_string();
+/// match try_pam_session(username) {
+///     Ok(()) => println!("Session created successfully"),
+///     Err(e) => println!("Failed to create session: {}", e),
+/// }
+/// ```
+///
+pub fn try_pam_session(username: String) -> Result<(), PamError> {
+    open_and_close_session(DEFAULT_PAM_SERVICE, &username)
+}
+
+/// Like `try_pam_session`, but goes through the PAM stack of `service`
+/// instead of `DEFAULT_PAM_SERVICE`.
+///
+/// This allows reusing a stack the system already has instead of installing
+/// `/etc/pam.d/polyjuice` on every host. For triggering `pam_mkhomedir`,
+/// `login` is a reasonable choice: it is present almost everywhere and its
+/// session stack includes the distribution's shared session configuration,
+/// which is where `pam_mkhomedir` is enabled (`common-session` on Debian
+/// and Ubuntu, `system-auth` or `postlogin` on Red Hat and its relatives,
+/// all pulled in by `login`). Those shared files are not services of their
+/// own: they lack the account stack, so name the service that includes
+/// them, not the file itself. `su` and `sshd` work the same way but may
+/// carry extra modules (`pam_wheel`, `pam_nologin`) that get in the way.
+///
+/// # Errors
+///
+/// Returns `PamError::EmptyService` if `service` is empty,
+/// `PamError::InvalidService` if it contains a `/` (services are file names
+/// under `/etc/pam.d`), and otherwise the same errors as
+/// `try_pam_session`, naming `service`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::try_pam_session_with_service;
+///
+/// try_pam_session_with_service("example_user", "login").unwrap();
+/// ```
+pub fn try_pam_session_with_service(username: &str, service: &str) -> Result<(), PamError> {
+    validate_service(service)?;
+    open_and_close_session(service, username)
+}
+
+/// Like `try_pam_session_with_service`, but gives up if the PAM stack of
+/// `service` has not finished within `timeout`.
+///
+/// PAM calls are synchronous and a module that talks to the network (LDAP,
+/// Kerberos, SSSD) can block on it indefinitely. The session is therefore
+/// opened on a helper thread, and `PamError::Timeout` is returned if that
+/// thread has not finished by the deadline.
+///
+/// # Caveats
+///
+/// There is no way to cancel a PAM call, so on timeout the helper thread is
+/// abandoned, not stopped. The PAM stack may still complete later (and
+/// e.g. create the home directory), or keep hanging for the lifetime of the
+/// process.
+///
+/// # Errors
+///
+/// As for `try_pam_session_with_service`, and `PamError::Timeout` if the
+/// deadline passed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{try_pam_session_with_timeout, PamError, DEFAULT_PAM_SERVICE};
+///
+/// match try_pam_session_with_timeout("example_user", DEFAULT_PAM_SERVICE, Duration::from_secs(5)) {
+///     Ok(()) => println!("Session created successfully"),
+///     Err(PamError::Timeout(_)) => println!("PAM stack did not answer"),
+///     Err(e) => println!("Failed to create session: {}", e),
+/// }
+/// ```
+pub fn try_pam_session_with_timeout(
+    username: impl Into<String>,
+    service: &str,
+    timeout: Duration,
+) -> Result<(), PamError> {
+    validate_service(service)?;
+    let username = username.into();
+    let service = service.to_string();
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("polyjuice-pam-session".to_string())
+        .spawn(move || {
+            // the receiver is gone if we already timed out
+            let _ = tx.send(open_and_close_session(&service, &username));
+        })
+        .map_err(PamError::Thread)?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(PamError::Timeout(timeout)),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(PamError::Thread(io::Error::other(
+            "PAM session thread panicked",
+        ))),
+    }
+}
+
+impl PamError {
+    /// Whether opening the session might succeed if tried again.
+    ///
+    /// That is when account management or opening the session failed with
+    /// `AUTHINFO_UNAVAIL` or `SYSTEM_ERR`, as `pam_sss` does while the SSSD
+    /// cache warms up or the directory is unreachable. Other codes, such as
+    /// `USER_UNKNOWN` or `PERM_DENIED`, are answers that will not change.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            PamError::AcctMgmt(_, code) | PamError::OpenSession(_, code) => {
+                matches!(code, ErrorCode::AUTHINFO_UNAVAIL | ErrorCode::SYSTEM_ERR)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Like `try_pam_session_with_service`, but tries up to `attempts` times
+/// while the failure `is_transient`.
+///
+/// The first retry waits `backoff`, and each one after that twice as long
+/// as the one before; each retry is logged at debug level. A permanent
+/// failure is returned straight away. An `attempts` of 0 is taken as 1.
+///
+/// # Errors
+///
+/// As for `try_pam_session_with_service`. When there was more than one
+/// attempt and all of them failed, returns `PamError::RetriesExhausted` with the number of
+/// attempts made and the last error, which holds the last PAM code.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use polyjuice::{try_pam_session_with_retry, PamError};
+///
+/// match try_pam_session_with_retry("example_user", "login", 5, Duration::from_millis(200)) {
+///     Ok(()) => println!("Session created successfully"),
+///     Err(PamError::RetriesExhausted(attempts, e)) => {
+///         println!("Still failing after {} attempts: {}", attempts, e)
+///     }
+///     Err(e) => println!("Failed to create session: {}", e),
+/// }
+/// ```
+pub fn try_pam_session_with_retry(
+    username: &str,
+    service: &str,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<(), PamError> {
+    validate_service(service)?;
+    let attempts = attempts.max(1);
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match open_and_close_session(service, username) {
+            Err(e) if e.is_transient() && attempt < attempts => {
+                log::debug!(
+                    "PAM session for {} failed on attempt {} of {}, retrying in {:?}: {}",
+                    username,
+                    attempt,
+                    attempts,
+                    delay,
+                    e
+                );
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            Err(e) if e.is_transient() && attempts > 1 => {
+                return Err(PamError::RetriesExhausted(attempts, Box::new(e)))
+            }
+            result => return result,
+        }
+    }
+}
+
+/// An open PAM session, closed when the guard is dropped.
+///
+/// Returned by `open_pam_session`. Unlike `try_pam_session`, which closes the
+/// session again right away, this keeps it open for as long as the guard
+/// lives, so session-scoped setup done by the PAM stack (mounts, keyrings,
+/// a systemd login session) stays in place while the user's program runs.
+///
+/// Opening the session also establishes the user's PAM credentials
+/// (`pam_setcred` with `PAM_ESTABLISH_CRED`, and `PAM_REINITIALIZE_CRED`
+/// once the session is open), which modules such as `pam_group` and
+/// Kerberos modules act on; closing deletes them again. PAM reports a
+/// failure there like any other failure to open, as
+/// `PamError::OpenSession`.
+///
+/// The guard must outlive the spawned child: keep it until the child has
+/// been waited on, and drop it afterwards. Closing needs the same privileges
+/// as opening, so drop it before giving up root.
+pub struct PamSessionGuard {
+    context: Context<PasswordConversation>,
+    token: Option<SessionToken>,
+}
+
+impl std::fmt::Debug for PamSessionGuard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PamSessionGuard")
+            .field("user", &self.context.user().ok())
+            .finish()
+    }
+}
+
+impl Drop for PamSessionGuard {
+    fn drop(&mut self) {
+        if let Some(token) = self.token.take() {
+            let session = self.context.unleak_session(token);
+            if let Err(e) = session.close(Flag::SILENT) {
+                log::warn!("Failed closing PAM session: {}", e);
+            }
+        }
+    }
+}
+
+impl PamSessionGuard {
+    /// The environment the PAM stack set up for the session; see
+    /// `try_pam_session_env`.
+    pub fn env(&self) -> HashMap<String, String> {
+        env_map(&self.context.envlist())
+    }
+}
+
+/// Which side wins when a PAM session's environment is merged into a
+/// user's with `CommandBuilder::pam_env`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PamEnvPrecedence {
+    /// The session's value replaces the user's, as during a login.
+    #[default]
+    Pam,
+    /// The session only sets variables the user's environment lacks.
+    User,
+}
+
+impl PamEnvPrecedence {
+    pub(crate) fn merge(
+        self,
+        env: &mut HashMap<OsString, OsString>,
+        pam_env: &HashMap<String, String>,
+    ) {
+        for (key, value) in pam_env {
+            let key = OsString::from(key);
+            if self == PamEnvPrecedence::Pam || !env.contains_key(&key) {
+                env.insert(key, value.into());
+            }
+        }
+    }
+}
+
+/// Like `try_pam_session`, but returns the environment the PAM stack set up
+/// for the session.
+///
+/// Modules such as `pam_env` and `pam_systemd` export variables
+/// (`XDG_RUNTIME_DIR`, site-wide settings from `/etc/environment`) that a
+/// login would hand to the user's shell. They are read with
+/// `pam_getenvlist` after the session is opened, before it is closed again;
+/// values that are not valid UTF-8 are converted lossily.
+///
+/// The login shell runs after PAM during a real login, but PAM supplies the
+/// session's own settings, so these should be layered on top of the
+/// environment from `get_user_env`, overriding it where both set a variable.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_env, get_user_env, try_pam_session_env};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let mut env = get_user_env(user.name()).unwrap().into_map();
+/// env.extend(try_pam_session_env("example_user").unwrap());
+/// let cmd = cmd_as_user_with_env("R", &user, &env);
+/// ```
+pub fn try_pam_session_env(username: &str) -> Result<HashMap<String, String>, PamError> {
+    let mut context = new_context(
+        DEFAULT_PAM_SERVICE,
+        username,
+        PasswordConversation::new(None),
+    )?;
+    let session = open_session(&mut context, DEFAULT_PAM_SERVICE)?;
+    Ok(env_map(&session.envlist()))
+}
+
+/// Runs `program` with `args` as `username` inside a PAM session, which
+/// stays open until the program has exited.
+///
+/// This ties together what a launcher otherwise has to get right itself:
+/// the session is opened with `open_pam_session`, the command is set up as
+/// by `cmd_as_user` with the session's environment merged in as
+/// `CommandBuilder::pam_env` does (the session winning), and it is run to
+/// completion with stdin, stdout and stderr inherited. Only then is the
+/// session closed. If the command cannot be set up, spawned or waited on,
+/// the session is closed all the same before the error is returned.
+///
+/// # Errors
+///
+/// Returns `Error::EnvFetch` with `EnvError::InvalidUsername` for a name
+/// `get_user_env` would reject, `Error::UserNotFound` for an unknown user,
+/// `Error::Command` if the lookup timed out, `Error::Pam` if the session
+/// could not be opened, `Error::EnvFetch` if the environment could not be
+/// captured and `Error::Io` if the command could not be run. An unsuccessful exit is not
+/// an error; check the returned status.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::run_in_pam_session;
+///
+/// let status = run_in_pam_session("example_user", "Rscript", &["pipeline.R"]).unwrap();
+/// std::process::exit(status.code().unwrap_or(1));
+/// ```
+pub fn run_in_pam_session<S: AsRef<OsStr>>(
+    username: &str,
+    program: impl AsRef<OsStr>,
+    args: &[S],
+) -> Result<ExitStatus, crate::Error> {
+    // PAM takes any name, so check it before handing it over
+    validate_username(OsStr::new(username)).map_err(CmdError::FailedGettingEnv)?;
+    let user = get_user_by_name_with_timeout(username, DEFAULT_LOOKUP_TIMEOUT)?;
+    let session = open_pam_session(username)?;
+    let status = CommandBuilder::new()
+        .program(program)
+        .args(args)
+        .user(user)
+        .pam_env(session.env(), PamEnvPrecedence::Pam)
+        .build()?
+        .status()
+        .map_err(CmdError::SpawnFailed)?;
+    drop(session);
+    Ok(status)
+}
+
+fn env_map(list: &EnvList) -> HashMap<String, String> {
+    list.iter_tuples()
+        .map(|(key, value)| {
+            (
+                key.to_string_lossy().to_string(),
+                value.to_string_lossy().to_string(),
+            )
+        })
+        .collect()
+}
+
+/// Opens a PAM session for `username` and keeps it open until the returned
+/// guard is dropped.
+///
+/// # Errors
+///
+/// As for `try_pam_session`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_username, open_pam_session};
+///
+/// let session = open_pam_session("example_user").unwrap();
+/// let status = cmd_as_username("R", "example_user").unwrap().status();
+/// // only close the session once the child has exited
+/// drop(session);
+/// ```
+pub fn open_pam_session(username: &str) -> Result<PamSessionGuard, PamError> {
+    open_guarded_session(DEFAULT_PAM_SERVICE, username)
+}
+
+fn open_guarded_session(service: &str, username: &str) -> Result<PamSessionGuard, PamError> {
+    let context = new_context(service, username, PasswordConversation::new(None))?;
+    guard_session(context, service)
+}
+
+fn guard_session(
+    mut context: Context<PasswordConversation>,
+    service: &str,
+) -> Result<PamSessionGuard, PamError> {
+    let token = open_session(&mut context, service)?.leak();
+    Ok(PamSessionGuard {
+        context,
+        token: Some(token),
+    })
+}
+
+/// Authenticates `username` with `password` through the PAM stack of
+/// `service`, then opens a PAM session for them as `open_pam_session` does.
+///
+/// This is for launchers that collected the user's password themselves, in
+/// the manner of `sudo` or a web login, and whose PAM stack authenticates:
+/// the other functions here answer no prompts at all. Every prompt for a
+/// secret is answered with `password`; other prompts are refused, so stacks
+/// that want more (a one-time code) fail with `PamError::Authenticate`.
+/// Accounts with an empty password are not let in
+/// (`PAM_DISALLOW_NULL_AUTHTOK`).
+///
+/// `password` is taken by value and its bytes are overwritten with zeros
+/// once authentication is over, whatever the outcome. The copies handed to
+/// PAM in answer to its prompts are outside polyjuice's control.
+///
+/// # Errors
+///
+/// Returns `PamError::EmptyService` or `PamError::InvalidService` for a
+/// `service` that `try_pam_session_with_service` would reject,
+/// `PamError::WrongPassword` if the password was rejected, which callers can
+/// count towards a lockout, `PamError::Authenticate` if
+/// authentication failed otherwise, and the errors of `open_pam_session`
+/// after that.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{authenticate_pam_session, cmd_as_username, PamError, DEFAULT_PAM_SERVICE};
+///
+/// let password = String::from("hunter2");
+/// match authenticate_pam_session("example_user", DEFAULT_PAM_SERVICE, password) {
+///     Ok(session) => {
+///         cmd_as_username("R", "example_user").unwrap().status().unwrap();
+///         drop(session);
+///     }
+///     Err(PamError::WrongPassword(_)) => eprintln!("wrong password"),
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// ```
+pub fn authenticate_pam_session(
+    username: &str,
+    service: &str,
+    password: String,
+) -> Result<PamSessionGuard, PamError> {
+    // taken over first, so the password is wiped even if the service is bad
+    let conversation = PasswordConversation::new(Some(password));
+    validate_service(service)?;
+    let mut context = new_context(service, username, conversation)?;
+    let authenticated = context.authenticate(Flag::DISALLOW_NULL_AUTHTOK);
+    context.conversation_mut().forget();
+    authenticated.map_err(|e| match e.code() {
+        ErrorCode::AUTH_ERR => PamError::WrongPassword(service.to_string()),
+        code => PamError::Authenticate(service.to_string(), code),
+    })?;
+    guard_session(context, service)
+}
+
+/// Answers prompts for a secret with a password, if it has one, and refuses
+/// everything else like `pam_client::conv_null` does.
+struct PasswordConversation {
+    password: Option<Vec<u8>>,
+}
+
+impl PasswordConversation {
+    fn new(password: Option<String>) -> Self {
+        PasswordConversation {
+            password: password.map(String::into_bytes),
+        }
+    }
+
+    /// Overwrites the password and drops it.
+    fn forget(&mut self) {
+        if let Some(mut password) = self.password.take() {
+            for byte in password.iter_mut() {
+                // volatile, so the writes to a buffer about to be freed are
+                // not optimized away
+                unsafe { std::ptr::write_volatile(byte, 0) };
+            }
+        }
+    }
+}
+
+impl Drop for PasswordConversation {
+    fn drop(&mut self) {
+        self.forget();
+    }
+}
+
+impl ConversationHandler for PasswordConversation {
+    fn prompt_echo_on(&mut self, _msg: &CStr) -> Result<CString, ErrorCode> {
+        Err(ErrorCode::CONV_ERR)
+    }
+
+    fn prompt_echo_off(&mut self, _msg: &CStr) -> Result<CString, ErrorCode> {
+        let password = self.password.as_ref().ok_or(ErrorCode::CONV_ERR)?;
+        CString::new(password.clone()).map_err(|_| ErrorCode::CONV_ERR)
+    }
+
+    fn text_info(&mut self, _msg: &CStr) {}
+
+    fn error_msg(&mut self, _msg: &CStr) {}
+}
+
+fn new_context(
+    service: &str,
+    username: &str,
+    conversation: PasswordConversation,
+) -> Result<Context<PasswordConversation>, PamError> {
+    Context::new(
+        service,
+        Some(username), // Preset username
+        conversation,
+    )
+    .map_err(|e| PamError::ContextInit(service.to_string(), e))
+}
+
+fn validate_service(service: &str) -> Result<(), PamError> {
+    if service.is_empty() {
+        return Err(PamError::EmptyService);
+    }
+    if service.contains('/') {
+        return Err(PamError::InvalidService(service.to_string()));
+    }
+    Ok(())
+}
+
+/// Runs account management and opens the session.
+fn open_session<'a>(
+    context: &'a mut Context<PasswordConversation>,
+    service: &str,
+) -> Result<Session<'a, PasswordConversation>, PamError> {
+    context
+        .acct_mgmt(Flag::NONE)
+        .map_err(|e| PamError::AcctMgmt(service.to_string(), e.code()))?;
+    context
+        .open_session(Flag::SILENT)
+        .map_err(|e| PamError::OpenSession(service.to_string(), e.code()))
+}
+
+/// Opens a session and closes it again straight away, through the guard so
+/// that a failure to close is logged the same way.
+fn open_and_close_session(service: &str, username: &str) -> Result<(), PamError> {
+    open_guarded_session(service, username).map(drop)
+}
@@ -0,0 +1,71 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::process::Command;
+
+/// Variables to copy from the calling process into a command that otherwise
+/// only sees the user's environment.
+///
+/// `cmd_as_user` clears the caller's environment, which also drops the few
+/// variables that describe the caller's session and are usually wanted in
+/// the child: `SSH_AUTH_SOCK` for agent forwarding, `DISPLAY` and
+/// `XAUTHORITY` for X, `TERM` for curses programs. Nothing is passed through
+/// unless named here.
+///
+/// A name may contain `*` to match any run of characters, so `LC_*` covers
+/// every locale category. Variables the caller does not have are skipped,
+/// and the ones it does have override the user's value for the same name.
+///
+/// # Examples
+///
+/// ```
+/// use std::process::Command;
+/// use polyjuice::PassthroughVars;
+///
+/// std::env::set_var("LC_TIME", "en_GB.UTF-8");
+/// std::env::remove_var("DISPLAY");
+///
+/// let mut cmd = Command::new("printenv");
+/// cmd.env_clear();
+/// PassthroughVars::new(&["DISPLAY", "LC_*"]).apply(&mut cmd);
+/// let envs: Vec<_> = cmd.get_envs().collect();
+/// assert!(envs.contains(&("LC_TIME".as_ref(), Some("en_GB.UTF-8".as_ref()))));
+/// assert!(!envs.iter().any(|(key, _)| *key == "DISPLAY"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PassthroughVars {
+    patterns: Vec<String>,
+}
+
+impl PassthroughVars {
+    pub fn new(patterns: &[&str]) -> Self {
+        PassthroughVars {
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    /// Whether `key` is named by one of the patterns.
+    pub fn matches(&self, key: &OsStr) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), key.as_bytes()))
+    }
+
+    /// The caller's variables that are named by one of the patterns.
+    pub(crate) fn caller_vars(&self) -> impl Iterator<Item = (OsString, OsString)> + '_ {
+        std::env::vars_os().filter(|(key, _)| self.matches(key))
+    }
+
+    /// Sets the matching caller variables on `cmd`, over whatever it already has.
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.envs(self.caller_vars());
+    }
+}
+
+/// Matches `name` against `pattern`, where `*` matches any run of bytes.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| glob_match(rest, &name[skip..])),
+        Some((c, rest)) => name.first() == Some(c) && glob_match(rest, &name[1..]),
+    }
+}
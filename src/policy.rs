@@ -0,0 +1,170 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use users::User;
+
+use crate::lookup::user_group_ids;
+use crate::CmdError;
+
+/// Who a program rule applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Subject {
+    Everyone,
+    User(String),
+    /// Members of the group, whether as primary or supplementary group.
+    Group(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    access: Access,
+    subject: Subject,
+    path: PathBuf,
+}
+
+/// Restricts which programs may be launched for which users.
+///
+/// Rules name a program by absolute path, or a directory to cover every
+/// program below it. Both the rule and the program being checked are
+/// canonicalized before comparing, and a bare program name is first resolved
+/// against the `PATH` the child will run with, so neither a symlink nor a
+/// crafted `PATH` can dress up a denied program as an allowed one.
+///
+/// # Precedence
+///
+/// 1. A matching deny rule always rejects, even if an allow rule matches too.
+/// 2. If any allow rule applies to the user, the user is on an allowlist and
+///    only programs matched by one of those allow rules are permitted.
+/// 3. Otherwise the program is permitted.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user_with_program_policy, ProgramPolicy, Subject};
+///
+/// let mut policy = ProgramPolicy::new();
+/// policy
+///     .allow(Subject::Group("analysts".into()), "/usr/bin/")
+///     .deny(Subject::Everyone, "/usr/bin/su");
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let cmd = cmd_as_user_with_program_policy("R", user, &policy);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ProgramPolicy {
+    rules: Vec<Rule>,
+}
+
+impl ProgramPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&mut self, subject: Subject, path: impl Into<PathBuf>) -> &mut Self {
+        self.push(Access::Allow, subject, path.into())
+    }
+
+    pub fn deny(&mut self, subject: Subject, path: impl Into<PathBuf>) -> &mut Self {
+        self.push(Access::Deny, subject, path.into())
+    }
+
+    fn push(&mut self, access: Access, subject: Subject, path: PathBuf) -> &mut Self {
+        self.rules.push(Rule {
+            access,
+            subject,
+            path,
+        });
+        self
+    }
+
+    /// Resolves `program` and checks it against the policy for `user`.
+    ///
+    /// `search_path` is the `PATH` used to resolve a program given without a
+    /// slash; pass the one the child will run with. On success the resolved
+    /// absolute path is returned, and that path (not `program`) is what should
+    /// be executed so the check and the exec agree.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CmdError::ProgramNotFound` if the program cannot be resolved
+    /// and `CmdError::ProgramNotPermitted` if the policy rejects it.
+    pub fn check(
+        &self,
+        user: &User,
+        program: impl AsRef<OsStr>,
+        search_path: Option<&OsStr>,
+    ) -> Result<PathBuf, CmdError> {
+        let program = program.as_ref();
+        let resolved = resolve_program(program, search_path)
+            .ok_or_else(|| CmdError::ProgramNotFound(program.to_os_string()))?;
+
+        let groups = user_group_names(user);
+        let applies = |subject: &Subject| match subject {
+            Subject::Everyone => true,
+            Subject::User(name) => user.name() == OsStr::new(name),
+            Subject::Group(name) => groups.iter().any(|g| g == OsStr::new(name)),
+        };
+        let matching = |access: Access| {
+            self.rules
+                .iter()
+                .filter(move |r| r.access == access && applies(&r.subject))
+        };
+
+        if matching(Access::Deny).any(|r| covers(&r.path, &resolved)) {
+            return Err(CmdError::ProgramNotPermitted(resolved));
+        }
+        let mut allows = matching(Access::Allow).peekable();
+        if allows.peek().is_some() && !allows.any(|r| covers(&r.path, &resolved)) {
+            return Err(CmdError::ProgramNotPermitted(resolved));
+        }
+        Ok(resolved)
+    }
+}
+
+fn covers(rule: &Path, program: &Path) -> bool {
+    let rule = fs::canonicalize(rule).unwrap_or_else(|_| rule.to_path_buf());
+    if rule.is_dir() {
+        program.starts_with(&rule)
+    } else {
+        program == rule
+    }
+}
+
+/// Finds the canonical path of the file `program` would exec, the way
+/// `execvp` does: as given if it contains a slash, otherwise the first
+/// executable match in `search_path`.
+fn resolve_program(program: &OsStr, search_path: Option<&OsStr>) -> Option<PathBuf> {
+    if program.is_empty() {
+        return None;
+    }
+    if program.as_bytes().contains(&b'/') {
+        return fs::canonicalize(program).ok();
+    }
+    std::env::split_paths(search_path.unwrap_or_default())
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+        .and_then(|path| fs::canonicalize(path).ok())
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+fn user_group_names(user: &User) -> Vec<OsString> {
+    user_group_ids(user)
+        .into_iter()
+        .filter_map(users::get_group_by_gid)
+        .map(|g| g.name().to_os_string())
+        .collect()
+}
@@ -0,0 +1,431 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use users::User;
+
+use crate::lookup::user_group_ids;
+use crate::CmdError;
+
+/// Which groups a launched command runs with.
+///
+/// - `Supplementary` gives it the primary group plus every group that lists
+///   the user as a member, as a login would.
+/// - `PrimaryOnly` gives it just the primary group, e.g. to keep a job out of
+///   shared project directories the user could otherwise reach.
+///
+/// Setting groups needs root. Without it the command keeps the caller's
+/// groups, so if those are not the ones asked for, building it fails with
+/// `CmdError::GroupsNotSettable` instead of running it with the wrong
+/// groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GroupPolicy {
+    #[default]
+    Supplementary,
+    PrimaryOnly,
+}
+
+/// Makes `cmd` start its own session before exec.
+///
+/// The child calls `setsid`, so it leads a new session and process group
+/// with no controlling terminal. Signals sent to the caller's process group,
+/// such as the `SIGINT` from a Ctrl-C or the `SIGHUP` when the caller's
+/// terminal goes away, no longer reach it. Interactive programs that should
+/// stop along with the caller must not be detached.
+///
+/// Having no controlling terminal, a detached child cannot open `/dev/tty`.
+/// A program that needs one has to be given a new terminal: the slave side
+/// of a pseudo-terminal, opened after `setsid`, becomes the controlling
+/// terminal of the new session.
+///
+/// # Examples
+///
+/// ```
+/// use std::process::Command;
+/// use polyjuice::detach;
+///
+/// let mut cmd = Command::new("cat");
+/// cmd.arg("/proc/self/stat");
+/// detach(&mut cmd);
+/// let output = cmd.output().unwrap();
+/// let stat = String::from_utf8(output.stdout).unwrap();
+/// let fields: Vec<&str> = stat.split(' ').collect();
+/// // the child leads its own session
+/// assert_eq!(fields[5], fields[0]);
+/// assert_ne!(fields[5].parse::<i32>().unwrap(), unsafe { libc::getsid(0) });
+/// ```
+pub fn detach(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Makes `cmd` set its file mode creation mask to `mode` before exec.
+pub(crate) fn set_umask(cmd: &mut Command, mode: u32) {
+    unsafe {
+        cmd.pre_exec(move || {
+            libc::umask(mode as libc::mode_t);
+            Ok(())
+        });
+    }
+}
+
+/// Makes `cmd` set its nice value to `nice` before exec.
+pub(crate) fn set_nice(cmd: &mut Command, nice: i32) {
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Makes `cmd` set `PR_SET_NO_NEW_PRIVS` before exec, so neither it nor
+/// anything it runs can gain privileges through setuid or setgid binaries
+/// or file capabilities. Needs Linux 3.5 or later; on older kernels
+/// spawning fails with `EINVAL`.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_no_new_privs(cmd: &mut Command) {
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Makes `cmd` ask for `signal` when the thread that spawns it exits.
+///
+/// The kernel clears the setting when the process changes its credentials,
+/// so this has to be added after the privilege drop. If the parent is gone
+/// before the setting takes effect, spawning fails with `ESRCH` instead of
+/// leaving the command running unwatched. `PR_SET_PDEATHSIG` only exists on
+/// Linux; elsewhere spawning fails with `io::ErrorKind::Unsupported`.
+pub(crate) fn set_parent_death_signal(cmd: &mut Command, signal: i32) {
+    #[cfg(target_os = "linux")]
+    {
+        let parent = std::process::id() as libc::pid_t;
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, signal as libc::c_ulong, 0, 0, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::getppid() != parent {
+                    return Err(io::Error::from_raw_os_error(libc::ESRCH));
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    unsafe {
+        let _ = signal;
+        cmd.pre_exec(|| {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "parent death signals are only available on Linux",
+            ))
+        });
+    }
+}
+
+#[cfg(target_os = "linux")]
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// `struct __user_cap_header_struct` from `linux/capability.h`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+/// `struct __user_cap_data_struct` from `linux/capability.h`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Empties the ambient and inheritable capability sets of the calling
+/// process, which needs no privileges.
+///
+/// Runs in the child; must not allocate. Kernels before 4.3 have no ambient
+/// set, which is treated as already empty. The inheritable set is only
+/// written if it is not empty already.
+#[cfg(target_os = "linux")]
+fn clear_inherited_capabilities() -> io::Result<()> {
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    if res == -1 && io::Error::last_os_error().raw_os_error() != Some(libc::EINVAL) {
+        return Err(io::Error::last_os_error());
+    }
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if data.iter().any(|d| d.inheritable != 0) {
+        for d in &mut data {
+            d.inheritable = 0;
+        }
+        if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Empties the capability bounding set of the calling process.
+///
+/// Runs in the child; must not allocate. Dropping needs `CAP_SETPCAP`, so
+/// this must run before `setuid`.
+#[cfg(target_os = "linux")]
+fn drop_bounding_set() -> io::Result<()> {
+    // PR_CAPBSET_READ fails past the last capability the kernel knows
+    let mut cap = 0;
+    while unsafe { libc::prctl(libc::PR_CAPBSET_READ, cap, 0, 0, 0) } >= 0 {
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        cap += 1;
+    }
+    Ok(())
+}
+
+/// The identity change a child makes between fork and exec.
+///
+/// std's `CommandExt::uid` and `gid` clear the supplementary groups when
+/// running as root and cannot set them (`CommandExt::groups` is unstable),
+/// and `pre_exec` closures run only after std has already called `setuid`,
+/// when `setgroups` is no longer permitted. So when running as root the whole
+/// drop is done here in a single `pre_exec` closure: `setgroups`, then
+/// `setgid`, then `setuid`.
+pub(crate) struct PrivilegeDrop {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: Vec<libc::gid_t>,
+    dir: Option<PathBuf>,
+    #[cfg(target_os = "linux")]
+    keep_inherited_capabilities: bool,
+    #[cfg(target_os = "linux")]
+    drop_capabilities: bool,
+}
+
+impl PrivilegeDrop {
+    /// The drop to `user`, with the groups `policy` asks for.
+    ///
+    /// The kernel accepts at most `NGROUPS_MAX` groups. Like `initgroups`, a
+    /// longer list is cut off there, keeping the primary group, which comes
+    /// first; a warning is logged.
+    pub(crate) fn to_user(user: &User, policy: GroupPolicy) -> Self {
+        let gid = user.primary_group_id();
+        let mut groups = match policy {
+            GroupPolicy::Supplementary => user_group_ids(user),
+            GroupPolicy::PrimaryOnly => vec![gid],
+        };
+        let max = unsafe { libc::sysconf(libc::_SC_NGROUPS_MAX) };
+        if max > 0 && truncate_groups(&mut groups, max as usize) {
+            log::warn!(
+                "{} is in more than the {} groups allowed; dropping the rest",
+                user.name().to_string_lossy(),
+                max
+            );
+        }
+        PrivilegeDrop {
+            uid: user.uid(),
+            gid,
+            groups,
+            dir: None,
+            #[cfg(target_os = "linux")]
+            keep_inherited_capabilities: false,
+            #[cfg(target_os = "linux")]
+            drop_capabilities: false,
+        }
+    }
+
+    /// Change to `dir` once running as the user, so that the user must be
+    /// allowed to enter it. `Command::current_dir` would change directory
+    /// before the switch when running as root.
+    pub(crate) fn in_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Leave the ambient and inheritable capability sets alone.
+    ///
+    /// By default both are emptied, since a login never hands capabilities
+    /// to a user and a caller running with some (say `CAP_DAC_OVERRIDE`
+    /// granted by systemd) would otherwise pass ambient ones on through
+    /// exec, or inheritable ones to binaries with inheritable file
+    /// capabilities.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn keeping_inherited_capabilities(mut self) -> Self {
+        self.keep_inherited_capabilities = true;
+        self
+    }
+
+    /// Also empty the capability bounding set before switching user, so that
+    /// not even a setuid-root binary can give the command capabilities.
+    ///
+    /// This needs `CAP_SETPCAP`, i.e. root. Without it spawning fails with
+    /// `EPERM` rather than running the command with capabilities it was
+    /// meant to lose.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn dropping_capabilities(mut self) -> Self {
+        self.drop_capabilities = true;
+        self
+    }
+
+    /// Makes `cmd` switch to this identity before exec.
+    ///
+    /// Without root privileges neither `setgroups` nor switching to another
+    /// user is possible, so only the uid and gid are passed to std, which
+    /// succeeds if they already are the current ones. Callers that can
+    /// report an error check `check` first; for the others, if the current
+    /// groups are not the ones this drop asks for, the command is set up to
+    /// fail with `EPERM` on spawn rather than run with the wrong groups.
+    pub(crate) fn apply(self, cmd: &mut Command) {
+        if users::get_effective_uid() != 0 {
+            cmd.uid(self.uid).gid(self.gid);
+            if let Some(dir) = &self.dir {
+                // std changes directory after setting the uid
+                cmd.current_dir(dir);
+            }
+            if let Err(e) = self.check() {
+                log::warn!("{}", e);
+                unsafe {
+                    cmd.pre_exec(|| Err(io::Error::from_raw_os_error(libc::EPERM)));
+                }
+            }
+            #[cfg(target_os = "linux")]
+            let (clear, drop) = (!self.keep_inherited_capabilities, self.drop_capabilities);
+            #[cfg(target_os = "linux")]
+            if clear || drop {
+                unsafe {
+                    cmd.pre_exec(move || {
+                        if clear {
+                            clear_inherited_capabilities()?;
+                        }
+                        if drop {
+                            drop_bounding_set()?;
+                        }
+                        Ok(())
+                    });
+                }
+            }
+            return;
+        }
+        // a path with a NUL in it cannot be entered; std fails those with
+        // EINVAL too
+        let dir = self
+            .dir
+            .as_ref()
+            .map(|dir| CString::new(dir.as_os_str().as_bytes()).ok());
+        unsafe {
+            // runs in the child; must not allocate
+            cmd.pre_exec(move || {
+                #[cfg(target_os = "linux")]
+                {
+                    if !self.keep_inherited_capabilities {
+                        clear_inherited_capabilities()?;
+                    }
+                    if self.drop_capabilities {
+                        drop_bounding_set()?;
+                    }
+                }
+                if libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) == -1
+                    || libc::setgid(self.gid) == -1
+                    || libc::setuid(self.uid) == -1
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                match &dir {
+                    Some(None) => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+                    Some(Some(dir)) if libc::chdir(dir.as_ptr()) == -1 => {
+                        return Err(io::Error::last_os_error());
+                    }
+                    _ => {}
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Fails with `CmdError::GroupsNotSettable` if this drop cannot be
+    /// applied as asked: the caller is not root and does not already have
+    /// the groups it asks for.
+    pub(crate) fn check(&self) -> Result<(), CmdError> {
+        if users::get_effective_uid() != 0 && !self.has_current_groups() {
+            return Err(CmdError::GroupsNotSettable(self.uid));
+        }
+        Ok(())
+    }
+
+    /// Whether the calling process already has exactly the groups of this
+    /// drop. `getgroups` may or may not list the effective gid, so the
+    /// primary group is counted on both sides.
+    fn has_current_groups(&self) -> bool {
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        let mut current = vec![0; count.max(0) as usize];
+        let count = unsafe { libc::getgroups(current.len() as _, current.as_mut_ptr()) };
+        if count < 0 {
+            return false;
+        }
+        current.truncate(count as usize);
+        current.push(unsafe { libc::getegid() });
+        let mut wanted = self.groups.clone();
+        wanted.push(self.gid);
+        current.sort_unstable();
+        current.dedup();
+        wanted.sort_unstable();
+        wanted.dedup();
+        current == wanted
+    }
+}
+
+/// Cuts `groups` down to the first `max`, returning whether any were dropped.
+fn truncate_groups(groups: &mut Vec<libc::gid_t>, max: usize) -> bool {
+    let dropped = groups.len() > max;
+    groups.truncate(max);
+    dropped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_past_the_limit_are_dropped_keeping_the_primary() {
+        let mut groups = vec![1000, 27, 44, 100];
+        assert!(truncate_groups(&mut groups, 2));
+        assert_eq!(groups, [1000, 27]);
+        assert!(!truncate_groups(&mut groups, 2));
+        assert_eq!(groups, [1000, 27]);
+    }
+}
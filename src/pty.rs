@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+
+/// A child running on a pseudo-terminal, from `spawn_in_pty`.
+///
+/// The handle holds the master side of the terminal: what the child writes
+/// to its stdout and stderr can be read from `reader`, and what is written
+/// to `writer` arrives on its stdin, after the usual line discipline (echo,
+/// `\r\n` line endings, `^C` becoming `SIGINT`, and so on). It dereferences
+/// to the `std::process::Child` for `id`, `wait` and the rest.
+#[derive(Debug)]
+pub struct PtyChild {
+    child: Child,
+    master: File,
+}
+
+impl PtyChild {
+    /// A reader for the child's output.
+    ///
+    /// Once every process holding the terminal has exited, Linux fails reads
+    /// on the master with `EIO` rather than returning end of file. The reader
+    /// turns that into end of file, so `read_to_end` and friends just stop.
+    pub fn reader(&self) -> io::Result<PtyReader> {
+        Ok(PtyReader(self.master.try_clone()?))
+    }
+
+    /// A writer for the child's input.
+    pub fn writer(&self) -> io::Result<File> {
+        self.master.try_clone()
+    }
+
+    /// Sets the terminal's window size. The child's foreground process group
+    /// is sent `SIGWINCH`.
+    pub fn resize(&self, rows: u16, cols: u16) -> io::Result<()> {
+        let size = window_size(rows, cols);
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ as _, &size) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Deref for PtyChild {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        &self.child
+    }
+}
+
+impl DerefMut for PtyChild {
+    fn deref_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+/// The output side of a `PtyChild`, see `PtyChild::reader`.
+#[derive(Debug)]
+pub struct PtyReader(File);
+
+impl Read for PtyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// Spawns `cmd` on a new pseudo-terminal of `rows` by `cols`.
+///
+/// The terminal becomes the stdin, stdout and stderr of the child, which
+/// runs in a session of its own with the terminal as its controlling
+/// terminal, as under a login. Programs that check `isatty` therefore see a
+/// terminal: they keep their colours, prompts and line editing.
+///
+/// `cmd` can come from the `cmd_as_user` family; the new session is set up
+/// after the switch to the target user, alongside the rest of the command's
+/// `pre_exec` hooks. A process that already leads a session or process
+/// group cannot start a new session, so `cmd` must not also be `detached`
+/// or in its `own_process_group`; the new session gives it both anyway.
+/// Any stdio already set on `cmd` is replaced. `cmd` is consumed so that
+/// this process keeps no handle on the terminal's child side, which is what
+/// lets the reader see the end of the output.
+///
+/// # Errors
+///
+/// Returns the error of `openpty` if no terminal could be allocated, or of
+/// `spawn`.
+///
+/// # Examples
+///
+/// ```
+/// use std::io::Read;
+/// use std::process::Command;
+/// use polyjuice::spawn_in_pty;
+///
+/// let mut cmd = Command::new("sh");
+/// cmd.args(["-c", "test -t 0 && test -t 1 && stty size"]);
+/// let mut child = spawn_in_pty(cmd, 40, 120).unwrap();
+///
+/// let mut output = String::new();
+/// child.reader().unwrap().read_to_string(&mut output).unwrap();
+/// assert!(child.wait().unwrap().success());
+/// assert_eq!(output.trim(), "40 120");
+/// ```
+///
+/// As another user:
+///
+/// ```no_run
+/// use std::io::{self, Write};
+/// use polyjuice::{cmd_as_username, spawn_in_pty};
+///
+/// let cmd = cmd_as_username("R", "example_user".to_string()).unwrap();
+/// let mut child = spawn_in_pty(cmd, 24, 80).unwrap();
+/// child.writer().unwrap().write_all(b"q()\n").unwrap();
+/// io::copy(&mut child.reader().unwrap(), &mut io::stdout()).unwrap();
+/// child.wait().unwrap();
+/// ```
+pub fn spawn_in_pty(mut cmd: Command, rows: u16, cols: u16) -> io::Result<PtyChild> {
+    let (master, slave) = open_pty(rows, cols)?;
+    let slave = File::from(slave);
+    cmd.stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // stdin is the terminal by now
+            if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = cmd.spawn()?;
+    Ok(PtyChild {
+        child,
+        master: File::from(master),
+    })
+}
+
+fn open_pty(rows: u16, cols: u16) -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut master = -1;
+    let mut slave = -1;
+    let size = window_size(rows, cols);
+    let opened = unsafe {
+        libc::openpty(
+            &mut master,
+            &mut slave,
+            std::ptr::null_mut(),
+            std::ptr::null(),
+            &size,
+        )
+    };
+    if opened == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    // the child only gets the slave side, as its stdio
+    for fd in [&master, &slave] {
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((master, slave))
+}
+
+fn window_size(rows: u16, cols: u16) -> libc::winsize {
+    libc::winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
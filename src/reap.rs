@@ -0,0 +1,162 @@
+use std::io;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Write end of the pipe the `SIGCHLD` handler wakes the reaper thread through.
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+static REAPER: Mutex<bool> = Mutex::new(false);
+
+/// Reaps every child of this process that has already terminated, without
+/// blocking.
+///
+/// Children that exit are kept around as zombies until their parent waits
+/// on them. A long-running process that starts children and never waits on
+/// them (detached or fire-and-forget launches) accumulates zombies, and each
+/// one holds a pid. Calling this periodically collects them. It returns the
+/// pid and exit status of each child reaped.
+///
+/// # Interaction with other waits
+///
+/// This waits on *any* child (`waitpid(-1, WNOHANG)`), not just ones started
+/// through this crate. A child that has terminated but that the caller still
+/// means to wait on itself, e.g. with `std::process::Child::wait`, is reaped
+/// here too. Its status is then returned by this function, and the caller's
+/// own wait fails with `ECHILD`. Only use this in processes where every
+/// child whose status matters is waited on before it could be reaped here,
+/// or where no child's status matters at all.
+///
+/// # Examples
+///
+/// ```
+/// use std::process::Command;
+/// use polyjuice::reap_children;
+///
+/// // started and forgotten about
+/// let child = Command::new("true").spawn().unwrap();
+/// let pid = child.id();
+/// drop(child);
+///
+/// let mut reaped = Vec::new();
+/// while !reaped.iter().any(|(p, _)| *p == pid) {
+///     std::thread::sleep(std::time::Duration::from_millis(10));
+///     reaped.extend(reap_children());
+/// }
+/// ```
+pub fn reap_children() -> Vec<(u32, ExitStatus)> {
+    let mut reaped = Vec::new();
+    loop {
+        let mut status = 0;
+        let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+        match pid {
+            // nothing (more) has terminated, or there are no children at all
+            0 => break,
+            -1 if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => continue,
+            -1 => break,
+            pid => reaped.push((pid as u32, ExitStatus::from_raw(status))),
+        }
+    }
+    reaped
+}
+
+/// Starts a background thread that reaps terminated children as soon as
+/// they exit.
+///
+/// A `SIGCHLD` handler is installed that wakes the thread, which then calls
+/// `reap_children`; the statuses it collects are discarded. Calling this
+/// again once the thread is running does nothing.
+///
+/// This replaces any `SIGCHLD` handler already installed, and has the same
+/// caveat as `reap_children`, only more so: once it runs, any child may be
+/// reaped the moment it exits. A `Child::wait` on a child the caller
+/// started itself then loses the race and fails with `ECHILD`. Prefer the
+/// one-shot `reap_children` in processes that also wait on their own
+/// children.
+///
+/// Both functions are only available on Linux, as the wake-up pipe is made
+/// with `pipe2` and the handler preserves `errno` through the Linux
+/// `__errno_location`.
+///
+/// # Errors
+///
+/// Returns an error if the wake-up pipe, the signal handler or the thread
+/// could not be set up.
+///
+/// # Examples
+///
+/// ```no_run
+/// polyjuice::spawn_reaper_thread().unwrap();
+/// ```
+pub fn spawn_reaper_thread() -> io::Result<()> {
+    let mut running = REAPER.lock().unwrap_or_else(|e| e.into_inner());
+    if *running {
+        return Ok(());
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    // the reader blocks; only the handler's end must never block
+    unsafe {
+        let flags = libc::fcntl(read_fd, libc::F_GETFL);
+        libc::fcntl(read_fd, libc::F_SETFL, flags & !libc::O_NONBLOCK);
+    }
+    let spawned = thread::Builder::new()
+        .name("polyjuice-reaper".to_string())
+        .spawn(move || {
+            let mut buf = [0u8; 64];
+            loop {
+                reap_children();
+                let n = unsafe {
+                    libc::read(read_fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+                };
+                if n == 0
+                    || (n == -1 && io::Error::last_os_error().kind() != io::ErrorKind::Interrupted)
+                {
+                    unsafe { libc::close(read_fd) };
+                    return;
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        return Err(e);
+    }
+
+    WAKE_FD.store(write_fd, Ordering::SeqCst);
+    let installed = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigchld as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART | libc::SA_NOCLDSTOP;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut())
+    };
+    if installed == -1 {
+        let e = io::Error::last_os_error();
+        WAKE_FD.store(-1, Ordering::SeqCst);
+        // the thread sees end of file and cleans up after itself
+        unsafe { libc::close(write_fd) };
+        return Err(e);
+    }
+    *running = true;
+    Ok(())
+}
+
+extern "C" fn on_sigchld(_: libc::c_int) {
+    let fd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        // a full pipe already means a wake-up is pending
+        unsafe {
+            let saved = *libc::__errno_location();
+            libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1);
+            *libc::__errno_location() = saved;
+        }
+    }
+}
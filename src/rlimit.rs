@@ -0,0 +1,142 @@
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// A resource whose use `Rlimits` can cap; see `setrlimit(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Resource {
+    /// `RLIMIT_AS`: size of the address space, in bytes.
+    As,
+    /// `RLIMIT_CORE`: size of core dumps, in bytes; 0 disables them.
+    Core,
+    /// `RLIMIT_CPU`: CPU time, in seconds.
+    Cpu,
+    /// `RLIMIT_DATA`: size of the data segment and heap, in bytes.
+    Data,
+    /// `RLIMIT_FSIZE`: size of files the process may create, in bytes.
+    Fsize,
+    /// `RLIMIT_MEMLOCK`: memory that may be locked into RAM, in bytes.
+    Memlock,
+    /// `RLIMIT_NOFILE`: one more than the highest file descriptor number.
+    Nofile,
+    /// `RLIMIT_NPROC`: processes (threads, on Linux) of the real user.
+    Nproc,
+    /// `RLIMIT_STACK`: size of the main thread's stack, in bytes.
+    Stack,
+}
+
+impl Resource {
+    pub(crate) const ALL: [Resource; 9] = [
+        Resource::As,
+        Resource::Core,
+        Resource::Cpu,
+        Resource::Data,
+        Resource::Fsize,
+        Resource::Memlock,
+        Resource::Nofile,
+        Resource::Nproc,
+        Resource::Stack,
+    ];
+
+    pub(crate) fn raw(self) -> libc::c_int {
+        let raw = match self {
+            Resource::As => libc::RLIMIT_AS,
+            Resource::Core => libc::RLIMIT_CORE,
+            Resource::Cpu => libc::RLIMIT_CPU,
+            Resource::Data => libc::RLIMIT_DATA,
+            Resource::Fsize => libc::RLIMIT_FSIZE,
+            Resource::Memlock => libc::RLIMIT_MEMLOCK,
+            Resource::Nofile => libc::RLIMIT_NOFILE,
+            Resource::Nproc => libc::RLIMIT_NPROC,
+            Resource::Stack => libc::RLIMIT_STACK,
+        };
+        raw as libc::c_int
+    }
+}
+
+/// Resource limits to set in a child just before exec.
+///
+/// Each limit has a soft value, which the kernel enforces, and a hard value,
+/// the ceiling up to which the process may raise the soft one itself. Use
+/// `Rlimits::UNLIMITED` for no limit.
+///
+/// On a command from the `cmd_as_user` family the limits are set after the
+/// switch to the target user, so they are the user's to keep: lowering a
+/// limit always works, but raising a hard limit above the current one needs
+/// `CAP_SYS_RESOURCE`, which the child has given up by then. If `setrlimit`
+/// fails, for that or because the soft value exceeds the hard one, `spawn`
+/// returns its error instead of running the command without the limit.
+///
+/// # Examples
+///
+/// ```
+/// use std::process::Command;
+/// use polyjuice::{Resource, Rlimits};
+///
+/// let mut cmd = Command::new("sh");
+/// cmd.args(["-c", "ulimit -n"]);
+/// Rlimits::new()
+///     .limit(Resource::Nofile, 64, 64)
+///     .limit(Resource::Core, 0, Rlimits::UNLIMITED)
+///     .apply(&mut cmd);
+/// let output = cmd.output().unwrap();
+/// assert_eq!(String::from_utf8(output.stdout).unwrap().trim(), "64");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rlimits {
+    limits: Vec<(Resource, u64, u64)>,
+}
+
+impl Rlimits {
+    /// The value meaning no limit, `RLIM_INFINITY`.
+    pub const UNLIMITED: u64 = libc::RLIM_INFINITY;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit `resource` to `soft`, with `hard` as the ceiling. A later limit
+    /// for the same resource replaces an earlier one.
+    pub fn limit(&mut self, resource: Resource, soft: u64, hard: u64) -> &mut Self {
+        self.limits.retain(|(r, _, _)| *r != resource);
+        self.limits.push((resource, soft, hard));
+        self
+    }
+
+    /// The soft and hard value `resource` is limited to, if it is.
+    pub fn get(&self, resource: Resource) -> Option<(u64, u64)> {
+        self.limits
+            .iter()
+            .find(|(r, _, _)| *r == resource)
+            .map(|&(_, soft, hard)| (soft, hard))
+    }
+
+    /// Makes `cmd` set the limits before exec.
+    pub fn apply(&self, cmd: &mut Command) {
+        if self.limits.is_empty() {
+            return;
+        }
+        let limits: Vec<(libc::c_int, libc::rlimit)> = self
+            .limits
+            .iter()
+            .map(|&(resource, soft, hard)| {
+                let limit = libc::rlimit {
+                    rlim_cur: soft as libc::rlim_t,
+                    rlim_max: hard as libc::rlim_t,
+                };
+                (resource.raw(), limit)
+            })
+            .collect();
+        // this runs between fork and exec, so it must not allocate
+        unsafe {
+            cmd.pre_exec(move || {
+                for (resource, limit) in &limits {
+                    if libc::setrlimit(*resource as _, limit) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
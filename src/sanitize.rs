@@ -0,0 +1,96 @@
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsStr;
+
+/// Variables `EnvSanitizer::default` removes from a captured environment.
+///
+/// These change how the dynamic loader or an interpreter starts up rather
+/// than configuring the program itself, so a value a user's profile exports
+/// for their own shell easily breaks an unrelated tool launched with it.
+pub const DEFAULT_DENIED_VARS: &[&str] = &[
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+    "LD_AUDIT",
+    "LD_DEBUG",
+    "LD_DEBUG_OUTPUT",
+    "LD_PROFILE",
+    "GCONV_PATH",
+    "BASH_ENV",
+    "ENV",
+    "PYTHONSTARTUP",
+];
+
+/// Removes unwanted variables from a captured user environment.
+///
+/// The `cmd_as_user` family applies `EnvSanitizer::default()`, which denies
+/// `DEFAULT_DENIED_VARS`, to the environment it captures; use
+/// `cmd_as_user_with_sanitizer` to pass another one. `allow` takes a
+/// variable off the deny list and `deny` adds one, and `EnvSanitizer::none()`
+/// keeps everything. An environment handed to `cmd_as_user_with_env` is used
+/// as given.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use polyjuice::EnvSanitizer;
+///
+/// let mut env = HashMap::from([
+///     ("LD_PRELOAD", "/home/u/lib/hook.so"),
+///     ("LD_LIBRARY_PATH", "/opt/R/lib"),
+///     ("CUSTOM_VAR", "1"),
+///     ("PATH", "/usr/bin"),
+/// ]);
+/// let mut sanitizer = EnvSanitizer::default();
+/// sanitizer.allow("LD_LIBRARY_PATH").deny("CUSTOM_VAR");
+/// sanitizer.sanitize(&mut env);
+///
+/// let mut kept: Vec<_> = env.into_keys().collect();
+/// kept.sort();
+/// assert_eq!(kept, ["LD_LIBRARY_PATH", "PATH"]);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvSanitizer {
+    denied: BTreeSet<String>,
+}
+
+impl Default for EnvSanitizer {
+    fn default() -> Self {
+        EnvSanitizer {
+            denied: DEFAULT_DENIED_VARS.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+}
+
+impl EnvSanitizer {
+    /// A sanitizer that removes nothing.
+    pub fn none() -> Self {
+        EnvSanitizer {
+            denied: BTreeSet::new(),
+        }
+    }
+
+    pub fn allow(&mut self, var: &str) -> &mut Self {
+        self.denied.remove(var);
+        self
+    }
+
+    pub fn deny(&mut self, var: &str) -> &mut Self {
+        self.denied.insert(var.to_string());
+        self
+    }
+
+    /// Removes every denied variable from `env`.
+    pub fn sanitize<K: AsRef<OsStr>, V>(&self, env: &mut HashMap<K, V>) {
+        env.retain(|key, _| {
+            let key = key.as_ref();
+            let denied = key.to_str().is_some_and(|k| self.denied.contains(k));
+            if denied {
+                log::debug!(
+                    "Removing {} from the captured environment",
+                    key.to_string_lossy()
+                );
+            }
+            !denied
+        });
+    }
+}
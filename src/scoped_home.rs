@@ -0,0 +1,138 @@
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use users::User;
+
+use crate::home::{configured_home, ensure_home_then, HomeError, NoHomePolicy};
+
+/// A per-job home directory below the user's real home.
+///
+/// Pointing `HOME` at a fresh subdirectory keeps what one job writes to its
+/// dotfiles away from the user's real ones and from other jobs. The
+/// directory is created and, on drop, removed by a helper process running as
+/// the user, so a symlink the user planted in the path can only ever lead
+/// to places the user could have written to anyway.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_user, scoped_home};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let home = scoped_home(&user, ".polyjuice-jobs/1234").unwrap();
+/// let mut cmd = cmd_as_user("R", user.clone()).unwrap();
+/// home.apply(&mut cmd);
+/// cmd.status().unwrap();
+/// drop(home); // removes ~/.polyjuice-jobs/1234
+/// ```
+#[derive(Debug)]
+pub struct ScopedHome {
+    path: PathBuf,
+    uid: u32,
+    gid: u32,
+    remove_on_drop: bool,
+}
+
+/// Creates `subpath` below `user`'s home directory for use as a scoped
+/// `HOME`.
+///
+/// `subpath` must be relative, must not contain `..` and must name at least
+/// one directory, so the scoped home is always strictly below the real one;
+/// `.` components are ignored. If the user's real home does not exist yet
+/// it is created first, as by `ensure_home_then`. Missing intermediate
+/// directories are created as well; the scoped directory itself gets mode
+/// 0700. An existing directory is reused.
+///
+/// # Errors
+///
+/// Returns `HomeError::NoHomeConfigured` if the user has no home directory,
+/// any error from creating the real home, and `HomeError::CreateFailed` if
+/// `subpath` is not a plain relative path or the directory could not be
+/// created.
+pub fn scoped_home(user: &User, subpath: impl AsRef<Path>) -> Result<ScopedHome, HomeError> {
+    let subpath = subpath.as_ref();
+    let home = configured_home(user, NoHomePolicy::Error)?.expect("Error policy never skips");
+    let plain = subpath
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    // `.` components are dropped so that `.` or `./.` cannot name (and, on
+    // drop, remove) the home itself
+    let relative: PathBuf = subpath
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    if !plain || relative.as_os_str().is_empty() {
+        return Err(HomeError::CreateFailed(
+            home.join(subpath),
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scoped home must be a relative path below the home directory",
+            ),
+        ));
+    }
+
+    ensure_home_then(user, || ())?;
+    let scoped = ScopedHome {
+        path: home.join(relative),
+        uid: user.uid(),
+        gid: user.primary_group_id(),
+        remove_on_drop: true,
+    };
+    scoped
+        .run_as_user("mkdir", &["-p", "-m", "0700", "--"])
+        .map_err(|e| HomeError::CreateFailed(scoped.path.clone(), e))?;
+    Ok(scoped)
+}
+
+impl ScopedHome {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keeps the directory when this is dropped instead of removing it.
+    pub fn keep(&mut self) -> &mut Self {
+        self.remove_on_drop = false;
+        self
+    }
+
+    /// Points `HOME` in `cmd`'s environment at this directory.
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.env("HOME", &self.path);
+    }
+
+    /// Runs `program args... <path>` as the owning user.
+    fn run_as_user(&self, program: &str, args: &[&str]) -> io::Result<()> {
+        let status = Command::new(program)
+            .args(args)
+            .arg(&self.path)
+            .uid(self.uid)
+            .gid(self.gid)
+            .current_dir("/")
+            .env_clear()
+            .env("PATH", "/usr/bin:/bin")
+            .stdin(Stdio::null())
+            .status()?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!(
+                "{} {} exited with {}",
+                program,
+                self.path.display(),
+                status
+            )))
+        }
+    }
+}
+
+impl Drop for ScopedHome {
+    fn drop(&mut self) {
+        if self.remove_on_drop {
+            if let Err(e) = self.run_as_user("rm", &["-rf", "--"]) {
+                log::warn!("Failed to remove scoped home: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use users::User;
+
+use crate::env::interactive_shell;
+use crate::{CmdError, CommandBuilder, NologinPolicy};
+
+/// Creates a command that starts `user`'s login shell, as `machinectl shell`
+/// or `su -` would.
+///
+/// The shell comes from the user's passwd entry; if that is empty or not
+/// executable, `DEFAULT_FALLBACK_SHELL` is used instead. It is started as a
+/// login shell, with `-` in front of its name as `argv[0]`, so it reads the
+/// user's profile itself. Otherwise the command is set up as by
+/// `cmd_as_user`: the user's ids, their captured and sanitized environment
+/// and their home directory as working directory. Stdin, stdout and stderr
+/// are inherited, so spawning it from a terminal connects the shell to it.
+///
+/// A user whose shell refuses logins, such as `/usr/sbin/nologin`, is
+/// refused; see `shell_as_user_with_nologin_policy` to start
+/// `DEFAULT_FALLBACK_SHELL` for them instead.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` with `EnvError::ShellDisallowsLogin`
+/// for such a user, and `CmdError::FailedGettingEnv` if the environment
+/// could not be captured.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::shell_as_user;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let status = shell_as_user(&user).unwrap().status().unwrap();
+/// std::process::exit(status.code().unwrap_or(1));
+/// ```
+pub fn shell_as_user(user: &User) -> Result<Command, CmdError> {
+    shell_as_user_with_nologin_policy(user, NologinPolicy::default())
+}
+
+/// Like `shell_as_user`, handling a shell that refuses logins according to
+/// `policy`. With `NologinPolicy::Fallback`, `DEFAULT_FALLBACK_SHELL` is
+/// started instead and the environment captured with it.
+///
+/// # Errors
+///
+/// As for `shell_as_user`; `EnvError::ShellDisallowsLogin` only under
+/// `NologinPolicy::Reject`.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{shell_as_user_with_nologin_policy, NologinPolicy};
+///
+/// let user = users::get_user_by_name("www-data").unwrap();
+/// let mut shell = shell_as_user_with_nologin_policy(&user, NologinPolicy::Fallback).unwrap();
+/// shell.status().unwrap();
+/// ```
+pub fn shell_as_user_with_nologin_policy(
+    user: &User,
+    policy: NologinPolicy,
+) -> Result<Command, CmdError> {
+    // checked before capturing, so a refused user costs no su
+    let shell = interactive_shell(user, policy).map_err(CmdError::FailedGettingEnv)?;
+    let mut cmd = CommandBuilder::new()
+        .nologin_policy(policy)
+        .command(&shell, user)?;
+    let mut arg0 = OsString::from("-");
+    arg0.push(shell.file_name().unwrap_or(OsStr::new("sh")));
+    cmd.arg0(arg0);
+    Ok(cmd)
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes a captured environment to `path` so it can be replayed later with
+/// `cmd_as_user_with_env`.
+///
+/// The format matches `/proc/<pid>/environ`: one `KEY=VALUE` entry per
+/// variable, each terminated by a NUL byte. Values may contain newlines or
+/// quotes and still round-trip unchanged. Entries are sorted by key so two
+/// snapshots of the same environment are byte-identical.
+///
+/// The file is created with mode 0600, since environments routinely hold
+/// tokens and other secrets. It is written to a fresh temporary file next to
+/// `path` and then renamed over it, so an existing file at `path` is
+/// replaced atomically: readers see either the old snapshot or the new one,
+/// never a partial write, and a symlink planted at `path` is replaced rather
+/// than followed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{get_user_env, save_env_snapshot};
+///
+/// let env = get_user_env("example_user").unwrap();
+/// save_env_snapshot("/var/lib/polyjuice/example_user.env", env.as_map()).unwrap();
+/// ```
+pub fn save_env_snapshot(path: impl AsRef<Path>, env: &HashMap<String, String>) -> io::Result<()> {
+    let mut keys: Vec<_> = env.keys().collect();
+    keys.sort();
+    let mut contents = Vec::new();
+    for key in keys {
+        contents.extend_from_slice(key.as_bytes());
+        contents.push(b'=');
+        contents.extend_from_slice(env[key].as_bytes());
+        contents.push(0);
+    }
+    let path = path.as_ref();
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "snapshot path has no file name",
+        )
+    })?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp = path.with_file_name(temp_name);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o600)
+        .open(&temp)?;
+    let written = file
+        .write_all(&contents)
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Reads an environment written by `save_env_snapshot`.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read, or with
+/// `io::ErrorKind::InvalidData` if an entry has no `=` or is not valid UTF-8.
+///
+/// # Examples
+///
+/// ```
+/// use std::collections::HashMap;
+/// use polyjuice::{load_env_snapshot, save_env_snapshot};
+///
+/// let path = std::env::temp_dir().join(format!("polyjuice-doc-snapshot-{}", std::process::id()));
+/// let mut env = HashMap::new();
+/// env.insert("GREETING".to_string(), "hello\nworld".to_string());
+/// env.insert("EMPTY".to_string(), String::new());
+///
+/// save_env_snapshot(&path, &env).unwrap();
+/// assert_eq!(load_env_snapshot(&path).unwrap(), env);
+/// std::fs::remove_file(&path).unwrap();
+/// ```
+pub fn load_env_snapshot(path: impl AsRef<Path>) -> io::Result<HashMap<String, String>> {
+    let contents = fs::read(path)?;
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+    contents
+        .split(|&b| b == 0)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let entry = std::str::from_utf8(entry)
+                .map_err(|_| invalid("snapshot entry is not valid UTF-8"))?;
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| invalid("snapshot entry has no '='"))?;
+            Ok((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// Existing file descriptors to install as the child's stdin, stdout and stderr.
+///
+/// This is a lower level alternative to `Command::stdin` and friends for
+/// callers that already manage their own descriptors, such as pipes created
+/// by an event loop or handed over by a supervisor. Each descriptor is
+/// `dup2`'d onto 0, 1 or 2 in the child just before exec.
+///
+/// # Ownership
+///
+/// The descriptors are borrowed, never closed by polyjuice. They must stay
+/// open until `spawn` returns; after that the parent may close its copies
+/// whenever it likes, the child keeps its own duplicates. The originals may
+/// (and usually should) be `O_CLOEXEC`: only the duplicated 0/1/2 survive
+/// exec, so the child does not inherit stray copies of the caller's fds.
+///
+/// Descriptors set here take precedence over anything configured through
+/// `Command::stdin`/`stdout`/`stderr`, because they are installed after the
+/// standard library has set up stdio.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::os::unix::io::AsRawFd;
+/// use polyjuice::{cmd_as_username, StdioFds};
+///
+/// let log = std::fs::File::create("/tmp/job.log").unwrap();
+/// let mut cmd = cmd_as_username("ls", "example_user").unwrap();
+/// StdioFds::new()
+///     .stdout_fd(log.as_raw_fd())
+///     .stderr_fd(log.as_raw_fd())
+///     .apply(&mut cmd);
+/// let status = cmd.status().unwrap();
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StdioFds {
+    stdin: Option<RawFd>,
+    stdout: Option<RawFd>,
+    stderr: Option<RawFd>,
+}
+
+impl StdioFds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use `fd` as the child's standard input.
+    pub fn stdin_fd(&mut self, fd: RawFd) -> &mut Self {
+        self.stdin = Some(fd);
+        self
+    }
+
+    /// Use `fd` as the child's standard output.
+    pub fn stdout_fd(&mut self, fd: RawFd) -> &mut Self {
+        self.stdout = Some(fd);
+        self
+    }
+
+    /// Use `fd` as the child's standard error.
+    pub fn stderr_fd(&mut self, fd: RawFd) -> &mut Self {
+        self.stderr = Some(fd);
+        self
+    }
+
+    /// Installs the descriptors on `cmd`.
+    ///
+    /// If `dup2` fails in the child, `spawn` returns the corresponding error.
+    pub fn apply(&self, cmd: &mut Command) {
+        if *self == Self::default() {
+            return;
+        }
+        let fds = [self.stdin, self.stdout, self.stderr];
+        // this runs between fork and exec, so it must not allocate
+        unsafe {
+            cmd.pre_exec(move || redirect(&fds));
+        }
+    }
+}
+
+fn redirect(fds: &[Option<RawFd>; 3]) -> io::Result<()> {
+    // Move every source above the stdio range first, so a source that is
+    // itself 0, 1 or 2 is not clobbered by an earlier dup2 (e.g. swapping
+    // stdout and stderr). The temporaries are CLOEXEC and vanish on exec.
+    let mut temps = [None; 3];
+    for (temp, fd) in temps.iter_mut().zip(fds) {
+        if let Some(fd) = fd {
+            *temp = Some(check(unsafe {
+                libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 3)
+            })?);
+        }
+    }
+    for (target, temp) in temps.iter().enumerate() {
+        if let Some(temp) = temp {
+            // the duplicate created by dup2 does not carry FD_CLOEXEC
+            check(unsafe { libc::dup2(*temp, target as RawFd) })?;
+        }
+    }
+    Ok(())
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    if ret == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
@@ -0,0 +1,141 @@
+use std::ffi::OsStr;
+use std::io;
+use std::pin::Pin;
+use std::process::{ExitStatus, Stdio};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::mpsc;
+use users::User;
+
+use crate::{cmd_as_user_async, CmdError, StreamKind};
+
+/// How many lines may wait in an `OutputStream` before the child is paused.
+const BUFFERED_LINES: usize = 64;
+
+/// An item from `stream_output_as_user`.
+#[derive(Debug)]
+pub enum OutputLine {
+    /// A line of output, decoded lossily and without its trailing newline.
+    Line(StreamKind, String),
+    /// The last item: the child's exit status, once both streams have ended.
+    Exited(io::Result<ExitStatus>),
+}
+
+/// The output of a command started by `stream_output_as_user`.
+///
+/// Lines are buffered only up to a small fixed number. When the consumer
+/// falls behind, reading from the child stops, its pipes fill up and its
+/// writes block until the consumer catches up, so a slow consumer slows the
+/// child down instead of growing memory. Dropping the stream closes the
+/// pipes; the child then sees `EPIPE` (or `SIGPIPE`) on its next write.
+#[derive(Debug)]
+pub struct OutputStream {
+    lines: mpsc::Receiver<OutputLine>,
+}
+
+impl OutputStream {
+    /// Waits for the next item, or `None` after `OutputLine::Exited`.
+    pub async fn next(&mut self) -> Option<OutputLine> {
+        self.lines.recv().await
+    }
+}
+
+impl Stream for OutputStream {
+    type Item = OutputLine;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.lines.poll_recv(cx)
+    }
+}
+
+/// Runs `program` with `args` as `user`, streaming its stdout and stderr
+/// line by line as they arrive.
+///
+/// This is the async counterpart of `run_prefixed`. The user's environment
+/// is captured with `cmd_as_user_async`, so the calling task is not stalled
+/// by slow login profiles. The command is then spawned and its output
+/// forwarded by a background task, which must run on a tokio runtime with
+/// IO enabled. Lines from stdout and stderr interleave in the order they are
+/// read; the stream ends with `OutputLine::Exited`. A read error on either
+/// pipe ends that stream's lines early.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured and `CmdError::SpawnFailed` if the command could not be started.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{stream_output_as_user, OutputLine};
+///
+/// let runtime = tokio::runtime::Builder::new_current_thread()
+///     .enable_all()
+///     .build()
+///     .unwrap();
+/// runtime.block_on(async {
+///     let user = users::get_user_by_name("example_user").unwrap();
+///     let mut output = stream_output_as_user("R", ["-e", "print('hello')"], &user)
+///         .await
+///         .unwrap();
+///     while let Some(item) = output.next().await {
+///         match item {
+///             OutputLine::Line(kind, line) => println!("[{}] {}", kind, line),
+///             OutputLine::Exited(status) => println!("exited: {:?}", status),
+///         }
+///     }
+/// });
+/// ```
+pub async fn stream_output_as_user<I, S>(
+    program: impl AsRef<OsStr>,
+    args: I,
+    user: &User,
+) -> Result<OutputStream, CmdError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut child = cmd_as_user_async(program, user.clone())
+        .await?
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(CmdError::SpawnFailed)?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+
+    let (tx, lines) = mpsc::channel(BUFFERED_LINES);
+    tokio::spawn(async move {
+        tokio::join!(
+            forward_lines(StreamKind::Stdout, stdout, &tx),
+            forward_lines(StreamKind::Stderr, stderr, &tx),
+        );
+        let status = child.wait().await;
+        let _ = tx.send(OutputLine::Exited(status)).await;
+    });
+    Ok(OutputStream { lines })
+}
+
+async fn forward_lines(
+    kind: StreamKind,
+    stream: impl AsyncRead + Unpin,
+    tx: &mpsc::Sender<OutputLine>,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    while let Ok(n) = reader.read_until(b'\n', &mut line).await {
+        if n == 0 {
+            break;
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.strip_suffix('\n').unwrap_or(&text).to_string();
+        // waits while the consumer is behind; fails once it is gone
+        if tx.send(OutputLine::Line(kind, text)).await.is_err() {
+            break;
+        }
+        line.clear();
+    }
+}
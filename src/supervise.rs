@@ -0,0 +1,116 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::ExitStatus;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+use users::User;
+
+use crate::{cmd_as_user, CmdError};
+
+/// The signals `run_as_user_supervised` forwards.
+const FORWARDED: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+/// Only one supervised run can own the signal handlers at a time.
+static SUPERVISED: Mutex<()> = Mutex::new(());
+/// Process group of the supervised child, or 0 before it is spawned.
+static CHILD_GROUP: AtomicI32 = AtomicI32::new(0);
+/// A signal that arrived before the child was spawned.
+static PENDING: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward(signal: libc::c_int) {
+    // only async-signal-safe calls in here
+    match CHILD_GROUP.load(Ordering::SeqCst) {
+        0 => PENDING.store(signal, Ordering::SeqCst),
+        group => unsafe {
+            libc::kill(-group, signal);
+        },
+    }
+}
+
+/// Runs `program` with `args` as `user`, forwarding `SIGTERM` and `SIGINT`
+/// to it until it exits.
+///
+/// This is for wrappers run by a service manager: stopping the wrapper
+/// should stop the job cleanly rather than leave it running or kill it only
+/// through closed pipes. The command runs in a process group of its own,
+/// and while it runs, `SIGTERM` and `SIGINT` received by this process are
+/// sent to that whole group instead of acting on this process. Whatever the
+/// job does with them (clean up and exit, or ignore them) decides when this
+/// returns. Stdin, stdout and stderr are inherited.
+///
+/// The previous handling of both signals is restored before returning. The
+/// handlers are process-wide, so supervised runs in different threads take
+/// turns: a second call waits until the first has returned.
+///
+/// # Errors
+///
+/// Returns `CmdError::FailedGettingEnv` if the environment could not be
+/// captured and `CmdError::SpawnFailed` if the signal handlers could not be
+/// installed or the command could not be started or waited on.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::run_as_user_supervised;
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let status = run_as_user_supervised("Rscript", &["pipeline.R"], &user).unwrap();
+/// std::process::exit(status.code().unwrap_or(1));
+/// ```
+pub fn run_as_user_supervised<S: AsRef<OsStr>>(
+    program: impl AsRef<OsStr>,
+    args: &[S],
+    user: &User,
+) -> Result<ExitStatus, CmdError> {
+    let mut cmd = cmd_as_user(program, user.clone())?;
+    cmd.args(args).process_group(0);
+
+    let _turn = SUPERVISED.lock().unwrap_or_else(|e| e.into_inner());
+    CHILD_GROUP.store(0, Ordering::SeqCst);
+    PENDING.store(0, Ordering::SeqCst);
+    let previous = install_forwarding().map_err(CmdError::SpawnFailed)?;
+
+    let status = cmd.spawn().and_then(|mut child| {
+        let group = child.id() as libc::pid_t;
+        CHILD_GROUP.store(group, Ordering::SeqCst);
+        match PENDING.swap(0, Ordering::SeqCst) {
+            0 => {}
+            signal => unsafe {
+                libc::kill(-group, signal);
+            },
+        }
+        child.wait()
+    });
+
+    CHILD_GROUP.store(0, Ordering::SeqCst);
+    restore(&previous);
+    status.map_err(CmdError::SpawnFailed)
+}
+
+/// Points the forwarded signals at `forward`, returning their previous
+/// actions.
+fn install_forwarding() -> io::Result<Vec<(libc::c_int, libc::sigaction)>> {
+    let mut previous = Vec::with_capacity(FORWARDED.len());
+    for signal in FORWARDED {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = forward as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        let mut old: libc::sigaction = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        if unsafe { libc::sigaction(signal, &action, &mut old) } == -1 {
+            let e = io::Error::last_os_error();
+            restore(&previous);
+            return Err(e);
+        }
+        previous.push((signal, old));
+    }
+    Ok(previous)
+}
+
+fn restore(previous: &[(libc::c_int, libc::sigaction)]) {
+    for (signal, old) in previous {
+        unsafe { libc::sigaction(*signal, old, std::ptr::null_mut()) };
+    }
+}
@@ -0,0 +1,136 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const WTMP_PATH: &[u8] = b"/var/log/wtmp\0";
+
+extern "C" {
+    // glibc provides updwtmpx but the libc crate does not bind it on linux-gnu
+    fn updwtmpx(wtmpx_file: *const c_char, utmpx: *const libc::utmpx);
+}
+
+/// A login record in utmp/wtmp that lasts as long as this value.
+///
+/// Creating a `UtmpSession` writes a `USER_PROCESS` entry to utmp (so the
+/// session shows up in `who`/`w`) and appends it to wtmp (so it shows up in
+/// `last`). Dropping it replaces the utmp entry with a `DEAD_PROCESS` record
+/// and appends the matching logout to wtmp.
+///
+/// Keep the guard alive for as long as the launched process runs, then drop
+/// it after waiting on the child.
+///
+/// # Crash cleanup
+///
+/// The logout record is written on drop, which includes unwinding from a
+/// panic. If the launching process is killed outright the entry is left
+/// behind as a stale `USER_PROCESS`; most tools already treat entries whose
+/// `ut_pid` no longer exists as dead, and the next session recorded on the
+/// same line overwrites it.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{cmd_as_username, UtmpSession};
+///
+/// let mut child = cmd_as_username("bash", "example_user").unwrap().spawn().unwrap();
+/// let session = UtmpSession::record("example_user", child.id(), "pts/3").unwrap();
+/// child.wait().unwrap();
+/// drop(session);
+/// ```
+pub struct UtmpSession {
+    entry: libc::utmpx,
+}
+
+impl UtmpSession {
+    /// Records a login for `username` on behalf of the process `pid`.
+    ///
+    /// `line` is the terminal the session is attached to, relative to `/dev`
+    /// (e.g. `pts/3`); a leading `/dev/` is stripped. Sessions without a
+    /// terminal conventionally use a tag such as `polyjuice:notty`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if utmp could not be updated, which usually means the
+    /// caller lacks permission to write `/var/run/utmp`.
+    pub fn record(
+        username: impl AsRef<OsStr>,
+        pid: u32,
+        line: impl AsRef<OsStr>,
+    ) -> io::Result<Self> {
+        let line = line.as_ref().as_bytes();
+        let line = line.strip_prefix(b"/dev/").unwrap_or(line);
+
+        // utmpx has private padding fields, so it can only be built zeroed
+        let mut entry: libc::utmpx = unsafe { std::mem::zeroed() };
+        entry.ut_type = libc::USER_PROCESS;
+        entry.ut_pid = pid as libc::pid_t;
+        copy_field(&mut entry.ut_line, line);
+        // by convention the id is the tail of the line, e.g. "ts/3" for "pts/3"
+        copy_field(&mut entry.ut_id, &line[line.len().saturating_sub(4)..]);
+        copy_field(&mut entry.ut_user, username.as_ref().as_bytes());
+        set_time(&mut entry);
+
+        write_entry(&entry)?;
+        Ok(UtmpSession { entry })
+    }
+}
+
+impl std::fmt::Debug for UtmpSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UtmpSession")
+            .field("user", &field_str(&self.entry.ut_user))
+            .field("line", &field_str(&self.entry.ut_line))
+            .field("pid", &self.entry.ut_pid)
+            .finish()
+    }
+}
+
+impl Drop for UtmpSession {
+    fn drop(&mut self) {
+        self.entry.ut_type = libc::DEAD_PROCESS;
+        self.entry.ut_user = [0; libc::__UT_NAMESIZE];
+        set_time(&mut self.entry);
+        // nothing useful can be done about a failure here
+        let _ = write_entry(&self.entry);
+    }
+}
+
+fn write_entry(entry: &libc::utmpx) -> io::Result<()> {
+    let written = unsafe {
+        libc::setutxent();
+        let written = libc::pututxline(entry);
+        libc::endutxent();
+        written
+    };
+    if written.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { updwtmpx(WTMP_PATH.as_ptr() as *const c_char, entry) };
+    Ok(())
+}
+
+fn copy_field(dest: &mut [c_char], src: &[u8]) {
+    // utmp fields are fixed width and need not be NUL terminated when full
+    for (d, s) in dest.iter_mut().zip(src) {
+        *d = *s as c_char;
+    }
+}
+
+fn field_str(field: &[c_char]) -> String {
+    let bytes: Vec<u8> = field
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn set_time(entry: &mut libc::utmpx) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    entry.ut_tv.tv_sec = now.as_secs() as _;
+    entry.ut_tv.tv_usec = now.subsec_micros() as _;
+}
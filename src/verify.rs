@@ -0,0 +1,205 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+
+use crate::{CmdError, CommandBuilder, Resource, Rlimits};
+
+/// The identity a launched process ended up with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub euid: u32,
+    pub gid: u32,
+    pub egid: u32,
+    /// Supplementary groups, in the order the kernel reports them.
+    pub groups: Vec<u32>,
+    /// The limit on every `Resource`, whether set by the builder or
+    /// inherited.
+    pub limits: Rlimits,
+}
+
+/// Performs the full setup of the command `builder` describes in a throwaway
+/// child and reports the credentials and limits it ended up with, without
+/// running any program.
+///
+/// Every `pre_exec` step the builder asks for runs as it would for a real
+/// launch: the nice value, the privilege drop and working directory, the
+/// umask, limits, session and `no_new_privs`. Instead of calling exec, the
+/// child writes its credentials back through a pipe and exits. A
+/// misconfigured launch therefore shows up here as an error or as unexpected
+/// credentials, before any user program gets to run with the wrong identity.
+///
+/// The user's environment is not captured and the program need not be set;
+/// neither plays a part in the setup.
+///
+/// # Errors
+///
+/// Returns the errors of `CommandBuilder::build` other than those of the
+/// capture, and `CmdError::SpawnFailed` if the child could not be created or
+/// one of its setup steps (such as `setuid`) failed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use polyjuice::{verify_launch_plan, CommandBuilder, Resource};
+///
+/// let user = users::get_user_by_name("example_user").unwrap();
+/// let creds = verify_launch_plan(
+///     CommandBuilder::new()
+///         .user(user.clone())
+///         .limit(Resource::Nofile, 256, 1024),
+/// )
+/// .unwrap();
+/// assert_eq!(creds.uid, user.uid());
+/// assert_eq!(creds.gid, user.primary_group_id());
+/// assert_eq!(creds.limits.get(Resource::Nofile), Some((256, 1024)));
+/// ```
+pub fn verify_launch_plan(builder: &CommandBuilder) -> Result<Credentials, CmdError> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(CmdError::SpawnFailed(io::Error::last_os_error()));
+    }
+    let (mut reader, writer) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let max_groups = unsafe { libc::sysconf(libc::_SC_NGROUPS_MAX) }.max(0) as usize;
+    let mut groups = vec![0 as libc::gid_t; max_groups];
+    let mut limits = [libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    }; Resource::ALL.len()];
+    let write_fd = fds[1];
+
+    let mut cmd = builder.build_without_env()?;
+    unsafe {
+        // runs in the child after all other setup; must not allocate
+        cmd.pre_exec(move || {
+            for (resource, limit) in Resource::ALL.iter().zip(limits.iter_mut()) {
+                libc::getrlimit(resource.raw() as _, limit);
+            }
+            let pairs: [[libc::rlim_t; 2]; Resource::ALL.len()] =
+                std::array::from_fn(|i| [limits[i].rlim_cur, limits[i].rlim_max]);
+            write_all(
+                write_fd,
+                pairs.as_ptr() as *const u8,
+                std::mem::size_of_val(&pairs),
+            );
+            let ngroups = libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr());
+            let header = [
+                libc::getuid(),
+                libc::geteuid(),
+                libc::getgid(),
+                libc::getegid(),
+                ngroups.max(0) as u32,
+            ];
+            write_all(
+                write_fd,
+                header.as_ptr() as *const u8,
+                std::mem::size_of_val(&header),
+            );
+            write_all(
+                write_fd,
+                groups.as_ptr() as *const u8,
+                ngroups.max(0) as usize * std::mem::size_of::<libc::gid_t>(),
+            );
+            libc::_exit(0);
+        });
+    }
+
+    let spawned = cmd.spawn();
+    // the child holds its own copy; ours must go so the read sees EOF
+    drop(writer);
+    let mut child = spawned.map_err(CmdError::SpawnFailed)?;
+    let mut report = Vec::new();
+    let read = reader.read_to_end(&mut report);
+    child.wait().map_err(CmdError::SpawnFailed)?;
+    read.map_err(CmdError::SpawnFailed)?;
+
+    let limits_len = Resource::ALL.len() * 16;
+    if report.len() < limits_len {
+        return Err(incomplete_report());
+    }
+    let (limit_bytes, report) = report.split_at(limits_len);
+    let mut limits = Rlimits::new();
+    for (resource, pair) in Resource::ALL.iter().zip(limit_bytes.chunks_exact(16)) {
+        let soft = u64::from_ne_bytes(pair[..8].try_into().unwrap());
+        let hard = u64::from_ne_bytes(pair[8..].try_into().unwrap());
+        limits.limit(*resource, soft, hard);
+    }
+    let words: Vec<u32> = report
+        .chunks_exact(4)
+        .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    match words.as_slice() {
+        [uid, euid, gid, egid, ngroups, groups @ ..] if groups.len() == *ngroups as usize => {
+            Ok(Credentials {
+                uid: *uid,
+                euid: *euid,
+                gid: *gid,
+                egid: *egid,
+                groups: groups.to_vec(),
+                limits,
+            })
+        }
+        _ => Err(incomplete_report()),
+    }
+}
+
+fn incomplete_report() -> CmdError {
+    CmdError::SpawnFailed(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "incomplete credential report from child",
+    ))
+}
+
+unsafe fn write_all(fd: libc::c_int, mut buf: *const u8, mut len: usize) {
+    while len > 0 {
+        let n = libc::write(fd, buf as *const libc::c_void, len);
+        if n <= 0 {
+            return;
+        }
+        buf = buf.add(n as usize);
+        len -= n as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn launch_plan_has_every_group_of_the_user() {
+        let user = users::get_user_by_uid(users::get_current_uid()).unwrap();
+        let creds = match verify_launch_plan(CommandBuilder::new().user(user.clone())) {
+            Ok(creds) => creds,
+            // without root, the caller's groups need not be the user's
+            Err(CmdError::GroupsNotSettable(_)) => return,
+            Err(e) => panic!("{}", e),
+        };
+        assert_eq!((creds.uid, creds.euid), (user.uid(), user.uid()));
+        assert_eq!(creds.gid, user.primary_group_id());
+
+        let name = CString::new(user.name().as_bytes()).unwrap();
+        let mut expected: Vec<libc::gid_t> = vec![0; 65536];
+        let mut count = expected.len() as libc::c_int;
+        let res = unsafe {
+            libc::getgrouplist(
+                name.as_ptr(),
+                user.primary_group_id(),
+                expected.as_mut_ptr(),
+                &mut count,
+            )
+        };
+        assert!(res >= 0);
+        expected.truncate(count as usize);
+        let mut groups = creds.groups;
+        for list in [&mut groups, &mut expected] {
+            list.sort_unstable();
+            list.dedup();
+        }
+        assert_eq!(groups, expected);
+    }
+}